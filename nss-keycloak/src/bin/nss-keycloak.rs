@@ -0,0 +1,269 @@
+//! Diagnostic tool for nss-keycloak.
+//!
+//! Runs the steps of the NSS plugin one by one, directly against Keycloak and
+//! without any cache, and prints the result, the time and the full error of each.
+
+use std::process::ExitCode;
+use std::time::Instant;
+
+use anyhow::Result;
+use reqwest::blocking::Client;
+
+use nss_keycloak::config::{self, AuthMethod, Config};
+use nss_keycloak::keycloak::auth::{KeycloakAuth, TokenProvider};
+use nss_keycloak::keycloak::client::HttpClient;
+use nss_keycloak::keycloak::groups::{
+    get_group_by_gid, get_group_by_name, get_groups_by_user, list_groups, KeycloakGroup,
+};
+use nss_keycloak::keycloak::users::{get_user_by_name, get_user_by_uid, list_users, KeycloakUser};
+
+const USAGE: &str = "Usage: nss-keycloak [COMMAND]
+
+Commands:
+  check               load the config, get a token and list all users and groups (default)
+  config              print the configuration with secrets redacted
+  check-config [PATH] validate a configuration file, by default the configured one
+  token               get an access token
+  user <NAME|UID>     look up a user by name, or by uid if numeric
+  users               list all users
+  group <NAME|GID>    look up a group by name, or by gid if numeric
+  groups              list all groups
+  initgroups <USER>   list the groups of a user
+  help                print this help
+
+The configuration file is read from $NSSKEYCLOAK_CONFIG_FILE,
+or /etc/nss-keycloak/config.toml if not set.";
+
+enum Command {
+    Check,
+    Config,
+    CheckConfig(Option<String>),
+    Token,
+    User(String),
+    Users,
+    Group(String),
+    Groups,
+    Initgroups(String),
+    Help,
+}
+
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] | ["check"] => Ok(Command::Check),
+        ["config"] => Ok(Command::Config),
+        ["check-config"] => Ok(Command::CheckConfig(None)),
+        ["check-config", path] => Ok(Command::CheckConfig(Some(path.to_string()))),
+        ["token"] => Ok(Command::Token),
+        ["user", key] => Ok(Command::User(key.to_string())),
+        ["users"] => Ok(Command::Users),
+        ["group", key] => Ok(Command::Group(key.to_string())),
+        ["groups"] => Ok(Command::Groups),
+        ["initgroups", user] => Ok(Command::Initgroups(user.to_string())),
+        ["help" | "-h" | "--help"] => Ok(Command::Help),
+        _ => Err(format!("Invalid arguments: {}", args.join(" "))),
+    }
+}
+
+/// Indent the continuation lines of a multi-line message to the output of `step`
+fn indent(message: &str) -> String {
+    message.trim_end().replace('\n', "\n       ")
+}
+
+/// Run a single diagnostic step and print its duration and result.
+/// Returns None if the step failed.
+fn step<T>(description: &str, f: impl FnOnce() -> Result<T>) -> Option<T> {
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed().as_millis();
+    match result {
+        Ok(value) => {
+            println!("[ OK ] {} ({} ms)", description, elapsed);
+            Some(value)
+        }
+        Err(err) => {
+            println!("[FAIL] {} ({} ms)", description, elapsed);
+            println!("       error: {}", indent(&err.to_string()));
+            for cause in err.chain().skip(1) {
+                println!("       caused by: {}", indent(&cause.to_string()));
+            }
+            None
+        }
+    }
+}
+
+fn print_user(user: &KeycloakUser) {
+    println!(
+        "       {}:x:{}:{}:{}:{}:{}{}",
+        user.username,
+        user.uid,
+        user.gid,
+        user.gecos,
+        user.homedir,
+        user.loginshell,
+        if user.enabled { "" } else { " (disabled)" }
+    );
+}
+
+fn print_group(group: &KeycloakGroup) {
+    println!(
+        "       {}:x:{}:{}",
+        group.name,
+        group.gid,
+        group.members.join(",")
+    );
+}
+
+fn print_not_found(description: &str) {
+    println!("       {} not found", description);
+}
+
+/// Run the lookups of a command with a session to Keycloak
+fn run_lookups(command: &Command, config: &Config, client: &Client, access_token: &str) -> bool {
+    let keycloak = &config.keycloak;
+    let mapping = &config.mapping;
+    match command {
+        Command::User(key) => {
+            let user = match key.parse::<libc::uid_t>() {
+                Ok(uid) => step("get user by uid", || {
+                    get_user_by_uid(keycloak, mapping, access_token, uid, client)
+                }),
+                Err(_) => step("get user by name", || {
+                    get_user_by_name(keycloak, mapping, access_token, key, client)
+                }),
+            };
+            match user {
+                Some(Some(user)) => print_user(&user),
+                Some(None) => print_not_found("user"),
+                None => return false,
+            }
+        }
+        Command::Group(key) => {
+            let group = match key.parse::<libc::gid_t>() {
+                Ok(gid) => step("get group by gid", || {
+                    get_group_by_gid(keycloak, mapping, access_token, gid, client)
+                }),
+                Err(_) => step("get group by name", || {
+                    get_group_by_name(keycloak, mapping, access_token, key, client)
+                }),
+            };
+            match group {
+                Some(Some(group)) => print_group(&group),
+                Some(None) => print_not_found("group"),
+                None => return false,
+            }
+        }
+        Command::Initgroups(user) => {
+            match step("get groups by user", || {
+                get_groups_by_user(keycloak, mapping, access_token, user, client)
+            }) {
+                Some(Some(groups)) => groups.iter().for_each(print_group),
+                Some(None) => print_not_found("user"),
+                None => return false,
+            }
+        }
+        Command::Users | Command::Check => {
+            match step("list users", || {
+                list_users(keycloak, mapping, access_token, client)
+            }) {
+                Some(users) => users.iter().for_each(print_user),
+                None => return false,
+            }
+            if matches!(command, Command::Check) {
+                return run_lookups(&Command::Groups, config, client, access_token);
+            }
+        }
+        Command::Groups => {
+            match step("list groups", || {
+                list_groups(keycloak, mapping, access_token, client)
+            }) {
+                Some(groups) => groups.iter().for_each(print_group),
+                None => return false,
+            }
+        }
+        Command::Config | Command::CheckConfig(_) | Command::Token | Command::Help => {}
+    }
+    true
+}
+
+fn run(command: Command) -> bool {
+    if let Command::CheckConfig(path) = command {
+        let path = path.unwrap_or_else(config::get_config_path);
+        // reading the file also validates it
+        let valid = step(&format!("validate configuration {}", path), || {
+            config::read_config_file(&path)
+        });
+        return valid.is_some();
+    }
+    let Some(config) = step(
+        &format!("load configuration from {}", config::get_config_path()),
+        config::load_config,
+    ) else {
+        return false;
+    };
+    if matches!(command, Command::Config) {
+        return match step("format configuration", || config::to_redacted_toml(&config)) {
+            Some(formatted) => {
+                println!("{}", formatted);
+                true
+            }
+            None => false,
+        };
+    }
+
+    if config.keycloak.tls_insecure {
+        println!("       warning: keycloak.tls_insecure is set, the certificate of Keycloak is not verified");
+    }
+    let http_client = HttpClient::new(&config.keycloak);
+    let Some(client) = step("build HTTP client", || http_client.get()) else {
+        return false;
+    };
+    let Some(mut auth) = step("initialize authentication", || {
+        KeycloakAuth::new(&config.keycloak, &http_client)
+    }) else {
+        return false;
+    };
+    if config.keycloak.discovery {
+        let Some(token_url) = step("discover token endpoint", || auth.token_url()) else {
+            return false;
+        };
+        println!("       {}", token_url);
+    }
+    let grant = match config.keycloak.username {
+        Some(_) => "password",
+        None => "client credentials",
+    };
+    let auth_method = match config.keycloak.auth_method {
+        AuthMethod::ClientSecret => "client secret",
+        AuthMethod::PrivateKeyJwt => "signed JWT",
+        AuthMethod::TlsClientAuth => "client certificate",
+    };
+    let description = format!("get access token ({} grant, {})", grant, auth_method);
+    let Some(access_token) = step(&description, || Ok(auth.get_access_token()?.clone())) else {
+        return false;
+    };
+    if let Some(expires_in) = auth.access_token_expires_in() {
+        println!("       access token expires in {} s", expires_in.as_secs());
+    }
+    run_lookups(&command, &config, &client, &access_token)
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match parse_args(&args) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    if matches!(command, Command::Help) {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    if run(command) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
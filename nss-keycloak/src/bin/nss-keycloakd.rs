@@ -0,0 +1,42 @@
+//! Resolver daemon for nss-keycloak.
+//!
+//! Owns the Keycloak session and the cache, and answers the lookups of the NSS
+//! plugin over a Unix socket, so that only the daemon needs access to the
+//! client credentials. The socket path is read from `NSSKEYCLOAK_SOCKET`.
+
+use std::process::ExitCode;
+
+/// Log to stderr, which is collected by the service manager
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}: {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn main() -> ExitCode {
+    log::set_logger(&StderrLogger).expect("Failed to set logger");
+    log::set_max_level(log::LevelFilter::Info);
+
+    // fail early instead of answering all lookups as unavailable
+    if let Err(err) = nss_keycloak::CONFIG.as_ref() {
+        log::error!("{:#}", err);
+        return ExitCode::FAILURE;
+    }
+    match nss_keycloak::daemon::serve(&nss_keycloak::daemon::get_socket_path()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            log::error!("{:#}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
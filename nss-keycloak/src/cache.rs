@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+#[cfg(feature = "mock")]
+use mock_instant::Instant;
+#[cfg(not(feature = "mock"))]
+use std::time::Instant;
+
+use libnss::group::Group;
+use libnss::passwd::Passwd;
+
+use crate::config::CacheConfig;
+
+// expired entries are only removed on access, until a map grows beyond this size
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// An NSS entry that can be looked up by name and by id
+pub trait Entry: Clone {
+    fn name(&self) -> &str;
+    fn id(&self) -> u32;
+}
+
+impl Entry for Passwd {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn id(&self) -> u32 {
+        self.uid
+    }
+}
+
+impl Entry for Group {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn id(&self) -> u32 {
+        self.gid
+    }
+}
+
+/// Key of a single entry lookup
+#[derive(Clone, Copy)]
+pub enum Key<'a> {
+    Name(&'a str),
+    Id(u32),
+}
+
+/// A cached lookup result, `None` if the entry does not exist
+struct Cached<T> {
+    expires: Instant,
+    entry: Option<T>,
+}
+
+struct Maps<T> {
+    by_name: HashMap<String, Cached<T>>,
+    by_id: HashMap<u32, Cached<T>>,
+}
+
+/// Cache of the lookup results of one NSS database, keyed by name and by id
+pub struct Entries<T> {
+    maps: Mutex<Maps<T>>,
+}
+
+impl<T: Entry> Entries<T> {
+    fn new() -> Self {
+        Entries {
+            maps: Mutex::new(Maps {
+                by_name: HashMap::new(),
+                by_id: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Get a cached lookup result
+    /// Returns None if the key is not cached or has expired
+    /// Returns Some(None) if the entry is cached as not existing
+    pub fn get(&self, key: Key) -> Option<Option<T>> {
+        let mut maps = self.maps.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        let cached = match key {
+            Key::Name(name) => get_unexpired(&mut maps.by_name, name, now),
+            Key::Id(id) => get_unexpired(&mut maps.by_id, &id, now),
+        };
+        cached.map(|cached| cached.entry.clone())
+    }
+
+    /// Cache the result of a lookup. Found entries are cached by both
+    /// their name and id, so that a lookup by name also fills the lookup by id.
+    pub fn insert(&self, config: &CacheConfig, key: Key, entry: Option<T>) {
+        match entry {
+            Some(entry) => self.insert_all(config, [entry]),
+            None => {
+                let Some(expires) = expiration(config.negative_ttl) else {
+                    return;
+                };
+                let cached = || Cached {
+                    expires,
+                    entry: None,
+                };
+                let mut maps = self.maps.lock().unwrap_or_else(PoisonError::into_inner);
+                match key {
+                    Key::Name(name) => insert_pruned(&mut maps.by_name, name.to_string(), cached()),
+                    Key::Id(id) => insert_pruned(&mut maps.by_id, id, cached()),
+                }
+            }
+        }
+    }
+
+    /// Cache found entries, e.g. the result of an enumeration
+    pub fn insert_all(&self, config: &CacheConfig, entries: impl IntoIterator<Item = T>) {
+        let Some(expires) = expiration(config.positive_ttl) else {
+            return;
+        };
+        let mut maps = self.maps.lock().unwrap_or_else(PoisonError::into_inner);
+        for entry in entries {
+            let name = entry.name().to_string();
+            let id = entry.id();
+            let by_id = Cached {
+                expires,
+                entry: Some(entry.clone()),
+            };
+            insert_pruned(&mut maps.by_id, id, by_id);
+            let by_name = Cached {
+                expires,
+                entry: Some(entry),
+            };
+            insert_pruned(&mut maps.by_name, name, by_name);
+        }
+    }
+}
+
+/// Expiration time of an entry cached now for the given TTL in seconds.
+/// Returns None if the entry is not to be cached, as the TTL is 0 or too large.
+fn expiration(ttl: u64) -> Option<Instant> {
+    if ttl == 0 {
+        return None;
+    }
+    Instant::now().checked_add(Duration::from_secs(ttl))
+}
+
+/// Get an unexpired entry from a map, removing it if it has expired
+fn get_unexpired<'a, K, Q, T>(
+    map: &'a mut HashMap<K, Cached<T>>,
+    key: &Q,
+    now: Instant,
+) -> Option<&'a Cached<T>>
+where
+    K: std::borrow::Borrow<Q> + std::hash::Hash + Eq,
+    Q: std::hash::Hash + Eq + ?Sized,
+{
+    if map.get(key).is_some_and(|cached| cached.expires <= now) {
+        map.remove(key);
+    }
+    map.get(key)
+}
+
+/// Insert an entry into a map, removing all expired entries if the map has grown large
+fn insert_pruned<K: std::hash::Hash + Eq, T>(
+    map: &mut HashMap<K, Cached<T>>,
+    key: K,
+    cached: Cached<T>,
+) {
+    if map.len() >= PRUNE_THRESHOLD {
+        let now = Instant::now();
+        map.retain(|_, cached| cached.expires > now);
+    }
+    map.insert(key, cached);
+}
+
+/// In-process cache of passwd and group lookups
+pub struct Cache {
+    pub users: Entries<Passwd>,
+    pub groups: Entries<Group>,
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Cache {
+            users: Entries::new(),
+            groups: Entries::new(),
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// --- Unit tests -------------------------------------------------------------------------------
+// -----------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(name: &str, gid: u32) -> Group {
+        Group {
+            name: name.to_string(),
+            passwd: "x".to_string(),
+            gid,
+            members: vec![],
+        }
+    }
+
+    /// Test that found entries are cached by name and id, and missing entries by their key
+    #[test]
+    fn test_insert_and_get() {
+        let config = CacheConfig::default();
+        let cache = Entries::new();
+        assert!(cache.get(Key::Name("group01")).is_none());
+
+        cache.insert(&config, Key::Name("group01"), Some(group("group01", 500)));
+        cache.insert(&config, Key::Id(404), None);
+        assert_eq!(cache.get(Key::Id(500)).unwrap().unwrap().name, "group01");
+        assert_eq!(cache.get(Key::Name("group01")).unwrap().unwrap().gid, 500);
+        assert!(cache.get(Key::Id(404)).unwrap().is_none());
+        assert!(cache.get(Key::Name("group02")).is_none());
+
+        // an enumeration fills the lookups of all entries
+        cache.insert_all(&config, [group("group02", 501), group("group03", 502)]);
+        assert_eq!(cache.get(Key::Name("group02")).unwrap().unwrap().gid, 501);
+        assert_eq!(cache.get(Key::Id(502)).unwrap().unwrap().name, "group03");
+    }
+
+    /// Test that a TTL too large for an expiration time does not panic
+    #[test]
+    fn test_ttl_overflow() {
+        let config = CacheConfig {
+            positive_ttl: u64::MAX,
+            negative_ttl: u64::MAX,
+            ..CacheConfig::default()
+        };
+        let cache = Entries::new();
+        cache.insert(&config, Key::Name("group01"), Some(group("group01", 500)));
+        cache.insert(&config, Key::Id(404), None);
+        assert!(cache.get(Key::Name("group01")).is_none());
+        assert!(cache.get(Key::Id(404)).is_none());
+    }
+
+    /// Test that a TTL of zero disables caching
+    #[test]
+    fn test_disabled() {
+        let config = CacheConfig {
+            positive_ttl: 0,
+            negative_ttl: 0,
+            ..CacheConfig::default()
+        };
+        let cache = Entries::new();
+        cache.insert(&config, Key::Name("group01"), Some(group("group01", 500)));
+        cache.insert(&config, Key::Id(404), None);
+        assert!(cache.get(Key::Name("group01")).is_none());
+        assert!(cache.get(Key::Id(404)).is_none());
+    }
+
+    /// Test that positive and negative entries expire after their own TTL
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_expiry() {
+        use mock_instant::MockClock;
+
+        let config = CacheConfig {
+            positive_ttl: 60,
+            negative_ttl: 10,
+            ..CacheConfig::default()
+        };
+        let cache = Entries::new();
+        cache.insert(&config, Key::Id(500), Some(group("group01", 500)));
+        cache.insert(&config, Key::Id(404), None);
+
+        MockClock::advance(Duration::from_secs(10));
+        assert!(cache.get(Key::Id(500)).is_some());
+        assert!(cache.get(Key::Id(404)).is_none());
+
+        MockClock::advance(Duration::from_secs(50));
+        assert!(cache.get(Key::Id(500)).is_none());
+        assert!(cache.get(Key::Name("group01")).is_none());
+    }
+}
@@ -1,19 +1,152 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::cmp::Eq;
+use std::num::NonZeroUsize;
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
 pub struct KeycloakConfig {
     pub realm: String,
     pub client_id: String,
+    // the client secret is given inline, or read from a file or an environment variable.
+    // Relative file paths are resolved against $CREDENTIALS_DIRECTORY (systemd credentials).
+    #[serde(default)]
     pub client_secret: String,
+    pub client_secret_file: Option<String>,
+    pub client_secret_env: Option<String>,
+    // how the client authenticates itself at the token endpoint
+    #[serde(default)]
+    pub auth_method: AuthMethod,
+    // PEM encoded RSA or EC private key to sign client assertions for "private_key_jwt".
+    // Relative paths are resolved against $CREDENTIALS_DIRECTORY like secret files.
+    pub private_key_file: Option<String>,
+    #[serde(default)]
+    pub signing_algorithm: SigningAlgorithm,
+    // key id (kid) of the signing key, if the client has more than one key registered
+    pub key_id: Option<String>,
+    // PEM encoded client certificate and key for mutual TLS with Keycloak,
+    // also used to authenticate the client for "tls_client_auth"
+    pub tls_client_cert: Option<String>,
+    pub tls_client_key: Option<String>,
+    // PEM bundle of the CA certificates to trust instead of the system trust store
+    pub ca_file: Option<String>,
+    // SHA-256 fingerprint of the Keycloak server certificate, checked in addition
+    // to the certificate being trusted
+    pub tls_pin_sha256: Option<String>,
+    #[serde(default)]
+    pub tls_min_version: TlsVersion,
+    // accept any server certificate, only for development
+    #[serde(default)]
+    pub tls_insecure: bool,
+    // proxy for all requests to Keycloak, e.g. "http://proxy.example.com:3128".
+    // If set, the proxy environment variables of the process are ignored.
+    pub proxy: Option<String>,
+    // hosts, domains and IP networks to reach without the proxy
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+    // ignore HTTPS_PROXY, ALL_PROXY and NO_PROXY of the process when no proxy is configured,
+    // so that lookups connect the same way in every process
+    #[serde(default)]
+    pub ignore_env_proxy: bool,
     pub url: String,
+    // allow plain HTTP for other hosts than localhost
+    #[serde(default)]
+    pub allow_http: bool,
+    // base URL of the admin REST API, if it is served on another host than `url`
+    pub admin_url: Option<String>,
+    // issuer of the realm, as in the tokens, by default {url}/realms/{realm}
+    pub issuer: Option<String>,
+    // get the token endpoint from {issuer}/.well-known/openid-configuration
+    #[serde(default)]
+    pub discovery: bool,
+    // further Keycloak URLs to fail over to, in order, when `url` is down
+    #[serde(default)]
+    pub fallback_urls: Vec<String>,
+    // seconds to try a URL last after it failed
+    #[serde(default = "default_failover_cooldown")]
+    pub failover_cooldown: u64,
     // optional parameters. If provided, will request password grant type
     // else, request client credentials grant type
     pub username: Option<String>,
     pub password: Option<String>,
+    pub password_file: Option<String>,
+    pub password_env: Option<String>,
+    // number of entries requested per page for paginated Keycloak API calls
+    #[serde(default = "default_page_size")]
+    pub page_size: NonZeroUsize,
+    // seconds to keep idle connections to Keycloak open for reuse
+    #[serde(default = "default_pool_idle_timeout")]
+    pub pool_idle_timeout: u64,
+    // timeouts in seconds for connecting to Keycloak, for a single request
+    // and for all requests of a single NSS lookup
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+    #[serde(default = "default_lookup_timeout")]
+    pub lookup_timeout: u64,
+    // number of retries of failed GET requests, with a backoff starting at
+    // retry_backoff milliseconds and doubling for every retry
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    #[serde(default = "default_retry_backoff")]
+    pub retry_backoff: u64,
+    // consecutive failed requests after which no requests are sent to Keycloak
+    // for circuit_breaker_cooldown seconds, 0 to disable
+    #[serde(default = "default_circuit_breaker_threshold")]
+    pub circuit_breaker_threshold: u32,
+    #[serde(default = "default_circuit_breaker_cooldown")]
+    pub circuit_breaker_cooldown: u64,
+    // seconds to wait before sending credentials again that Keycloak rejected
+    #[serde(default = "default_bad_credentials_backoff")]
+    pub bad_credentials_backoff: u64,
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+fn default_failover_cooldown() -> u64 {
+    30
+}
+
+fn default_page_size() -> NonZeroUsize {
+    NonZeroUsize::new(100).unwrap()
+}
+
+fn default_pool_idle_timeout() -> u64 {
+    90
+}
+
+fn default_connect_timeout() -> u64 {
+    5
+}
+
+fn default_request_timeout() -> u64 {
+    10
+}
+
+pub(super) fn default_lookup_timeout() -> u64 {
+    30
+}
+
+fn default_retries() -> u32 {
+    2
+}
+
+fn default_retry_backoff() -> u64 {
+    100
+}
+
+fn default_circuit_breaker_threshold() -> u32 {
+    5
+}
+
+fn default_circuit_breaker_cooldown() -> u64 {
+    30
+}
+
+fn default_bad_credentials_backoff() -> u64 {
+    60
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
 pub struct MappingConfig {
     pub user_home: String,
     pub user_shell: String,
@@ -21,10 +154,153 @@ pub struct MappingConfig {
     pub user_uid: String,
     pub user_gid: String,
     pub group_gid: String,
+    // optional password aging attributes for the shadow database.
+    // Values are expected in days (since epoch for last change and expiry).
+    pub shadow_last_change: Option<String>,
+    pub shadow_min_days: Option<String>,
+    pub shadow_max_days: Option<String>,
+    pub shadow_warn_days: Option<String>,
+    pub shadow_inactive_days: Option<String>,
+    pub shadow_expire: Option<String>,
+    // how users that are disabled in Keycloak are exposed
+    #[serde(default)]
+    pub disabled_users: DisabledUserPolicy,
+    #[serde(default = "default_disabled_user_shell")]
+    pub disabled_user_shell: String,
+    // naming and membership of nested Keycloak groups
+    #[serde(default)]
+    pub group_naming: GroupNaming,
+    #[serde(default = "default_group_path_separator")]
+    pub group_path_separator: String,
+    #[serde(default)]
+    pub group_name_prefix: String,
+    #[serde(default)]
+    pub group_nested_members: bool,
+}
+
+/// Method the client uses to authenticate itself at the token endpoint
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    /// send the client secret with each token request
+    #[default]
+    ClientSecret,
+    /// send a client assertion JWT signed with the private key
+    PrivateKeyJwt,
+    /// authenticate with the client certificate of the TLS connection
+    TlsClientAuth,
+}
+
+/// Minimum TLS version for connections to Keycloak
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// Algorithm to sign client assertions with, must match the key type
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SigningAlgorithm {
+    #[default]
+    Rs256,
+    Rs384,
+    Rs512,
+    Ps256,
+    Ps384,
+    Ps512,
+    Es256,
+    Es384,
+}
+
+/// Policy for users that are disabled in Keycloak
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DisabledUserPolicy {
+    /// resolve disabled users with a forced login shell and a locked shadow entry,
+    /// so that file ownership is still displayed by name
+    #[default]
+    Lock,
+    /// do not resolve disabled users at all
+    Hide,
+}
+
+fn default_disabled_user_shell() -> String {
+    "/sbin/nologin".to_string()
+}
+
+/// Naming scheme for Keycloak groups, which may be nested
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupNaming {
+    /// use the name of the group itself, e.g. `platform` for `/eng/platform`
+    #[default]
+    Leaf,
+    /// use the full path of the group joined with the path separator,
+    /// e.g. `eng-platform` for `/eng/platform`
+    Path,
+}
+
+fn default_group_path_separator() -> String {
+    "-".to_string()
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    // seconds to cache entries found in Keycloak, 0 disables caching them
+    #[serde(default = "default_positive_ttl")]
+    pub positive_ttl: u64,
+    // seconds to cache lookups of entries that do not exist, 0 disables caching them
+    #[serde(default = "default_negative_ttl")]
+    pub negative_ttl: u64,
+    // directory of the persistent cache, which is served when Keycloak
+    // is unreachable. Disabled if not set.
+    pub directory: Option<String>,
+    // seconds an entry of the persistent cache is served after its last update
+    #[serde(default = "default_max_staleness")]
+    pub max_staleness: u64,
+    // seconds between the enumerations of nss-keycloakd that update the persistent cache
+    #[serde(default = "default_refresh_interval")]
+    pub refresh_interval: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            positive_ttl: default_positive_ttl(),
+            negative_ttl: default_negative_ttl(),
+            directory: None,
+            max_staleness: default_max_staleness(),
+            refresh_interval: default_refresh_interval(),
+        }
+    }
+}
+
+fn default_positive_ttl() -> u64 {
+    300
+}
+
+fn default_negative_ttl() -> u64 {
+    30
+}
+
+fn default_max_staleness() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_refresh_interval() -> u64 {
+    15 * 60
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub keycloak: KeycloakConfig,
     pub mapping: MappingConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
 }
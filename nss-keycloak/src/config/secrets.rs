@@ -0,0 +1,134 @@
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use super::model::KeycloakConfig;
+
+// set by systemd for services with LoadCredential= or SetCredential=
+const CREDENTIALS_DIRECTORY_ENV: &str = "CREDENTIALS_DIRECTORY";
+
+/// Resolve the path of a secret file. Relative paths are taken from the
+/// systemd credentials directory.
+pub(crate) fn secret_path(file: &str) -> Result<PathBuf> {
+    let path = Path::new(file);
+    if path.is_absolute() {
+        return Ok(path.to_path_buf());
+    }
+    let directory = std::env::var_os(CREDENTIALS_DIRECTORY_ENV).ok_or_else(|| {
+        anyhow!(
+            "relative path '{}' requires ${} to be set",
+            file,
+            CREDENTIALS_DIRECTORY_ENV
+        )
+    })?;
+    Ok(Path::new(&directory).join(path))
+}
+
+/// Read a secret from a file or an environment variable, if one of them is configured.
+/// A trailing newline is not part of the secret.
+fn read_secret(key: &str, file: Option<&str>, env: Option<&str>) -> Result<Option<String>> {
+    let secret = match (file, env) {
+        (Some(file), _) => {
+            let path = secret_path(file)
+                .with_context(|| format!("Failed to read keycloak.{}_file", key))?;
+            let secret = std::fs::read_to_string(&path).with_context(|| {
+                format!("Failed to read keycloak.{} from {}", key, path.display())
+            })?;
+            if std::fs::metadata(&path).is_ok_and(|metadata| metadata.mode() & 0o004 != 0) {
+                log::warn!(
+                    "{} is readable by all users, restrict it to the resolving user",
+                    path.display()
+                );
+            }
+            secret
+        }
+        (None, Some(env)) => std::env::var(env).with_context(|| {
+            format!(
+                "Failed to read keycloak.{} from environment variable {}",
+                key, env
+            )
+        })?,
+        (None, None) => return Ok(None),
+    };
+    let secret = secret.trim_end_matches(['\r', '\n']);
+    if secret.is_empty() {
+        return Err(anyhow!("keycloak.{} must not be empty", key));
+    }
+    Ok(Some(secret.to_string()))
+}
+
+/// Replace the secrets configured by file or environment variable with their values,
+/// so that the rest of the plugin only needs to look at the inline values.
+pub(super) fn resolve_secrets(config: &mut KeycloakConfig) -> Result<()> {
+    if let Some(secret) = read_secret(
+        "client_secret",
+        config.client_secret_file.as_deref(),
+        config.client_secret_env.as_deref(),
+    )? {
+        config.client_secret = secret;
+    }
+    if let Some(password) = read_secret(
+        "password",
+        config.password_file.as_deref(),
+        config.password_env.as_deref(),
+    )? {
+        config.password = Some(password);
+    }
+    Ok(())
+}
+
+// -----------------------------------------------------------------------------------------------
+// --- Unit tests -------------------------------------------------------------------------------
+// -----------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that secrets are read from absolute files, the credentials directory
+    /// and environment variables
+    #[test]
+    fn test_read_secret() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("client-secret");
+        std::fs::write(&path, "filesecret\n").unwrap();
+
+        assert_eq!(
+            read_secret("client_secret", path.to_str(), None).unwrap(),
+            Some("filesecret".to_string())
+        );
+        temp_env::with_var(CREDENTIALS_DIRECTORY_ENV, Some(directory.path()), || {
+            assert_eq!(
+                read_secret("client_secret", Some("client-secret"), None).unwrap(),
+                Some("filesecret".to_string())
+            );
+        });
+        temp_env::with_var_unset(CREDENTIALS_DIRECTORY_ENV, || {
+            let err = read_secret("client_secret", Some("client-secret"), None).unwrap_err();
+            assert!(format!("{:#}", err).contains("$CREDENTIALS_DIRECTORY"));
+        });
+        temp_env::with_var("NSSKEYCLOAK_TEST_SECRET", Some("envsecret"), || {
+            assert_eq!(
+                read_secret("client_secret", None, Some("NSSKEYCLOAK_TEST_SECRET")).unwrap(),
+                Some("envsecret".to_string())
+            );
+        });
+        assert_eq!(read_secret("client_secret", None, None).unwrap(), None);
+    }
+
+    /// Test that missing and empty secrets are errors
+    #[test]
+    fn test_read_secret_missing() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("password");
+        assert!(read_secret("password", path.to_str(), None).is_err());
+
+        std::fs::write(&path, "\n").unwrap();
+        let err = read_secret("password", path.to_str(), None).unwrap_err();
+        assert!(err.to_string().contains("must not be empty"));
+
+        temp_env::with_var_unset("NSSKEYCLOAK_TEST_PASSWORD", || {
+            assert!(read_secret("password", None, Some("NSSKEYCLOAK_TEST_PASSWORD")).is_err());
+        });
+    }
+}
@@ -0,0 +1,525 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use anyhow::{anyhow, Result};
+use reqwest::Url;
+
+use super::model::{AuthMethod, CacheConfig, Config, KeycloakConfig, MappingConfig};
+
+// upper limits of durations in seconds, far beyond any useful value, so that
+// deadlines and expiration times computed from them cannot overflow
+const MAX_TIMEOUT: u64 = 60 * 60;
+const MAX_DURATION: u64 = 366 * 24 * 60 * 60;
+
+/// Check if the host of a URL is the local machine
+fn is_loopback(url: &Url) -> bool {
+    match url.host_str() {
+        Some("localhost") => true,
+        Some(host) => host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback()),
+        None => false,
+    }
+}
+
+/// Parse a SHA-256 fingerprint as printed by `openssl x509 -noout -fingerprint -sha256`,
+/// hex digits optionally separated by colons
+pub(crate) fn parse_fingerprint(fingerprint: &str) -> Result<Vec<u8>> {
+    let hex: String = fingerprint.chars().filter(|c| *c != ':').collect();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!(
+            "'{}' is not a SHA-256 fingerprint of 32 hex encoded bytes",
+            fingerprint
+        ));
+    }
+    Ok((0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap_or_default())
+        .collect())
+}
+
+/// Check that a Keycloak URL can be used, HTTP is only accepted for localhost
+/// unless explicitly allowed
+pub(crate) fn check_url(key: &str, url: &str, allow_http: bool, problems: &mut Vec<String>) {
+    match Url::parse(url) {
+        Err(err) => problems.push(format!("{} '{}' is not a valid URL: {}", key, url, err)),
+        Ok(parsed) => match parsed.scheme() {
+            "https" => {}
+            "http" if allow_http || is_loopback(&parsed) => {}
+            "http" => problems.push(format!(
+                "{} '{}' uses plain HTTP, use https:// or set keycloak.allow_http = true",
+                key, url
+            )),
+            scheme => problems.push(format!(
+                "{} '{}' has unsupported scheme '{}', use https://",
+                key, url, scheme
+            )),
+        },
+    }
+}
+
+/// Check that a duration in seconds does not exceed its upper limit
+fn check_max(key: &str, value: u64, max: u64, problems: &mut Vec<String>) {
+    if value > max {
+        problems.push(format!("{} must be at most {}", key, max));
+    }
+}
+
+/// Check that a secret is configured by exactly one of its keys, if it is required
+fn check_secret_sources(
+    key: &str,
+    sources: &[Option<&str>],
+    required: bool,
+    problems: &mut Vec<String>,
+) {
+    let keys: Vec<&str> = sources.iter().flatten().copied().collect();
+    match keys.len() {
+        0 if required => problems.push(format!(
+            "keycloak.{0} is missing, set one of keycloak.{0}, keycloak.{0}_file or keycloak.{0}_env",
+            key
+        )),
+        0 | 1 => {}
+        _ => problems.push(format!(
+            "keycloak.{} are all set, only one of them may be used",
+            keys.join(", keycloak.")
+        )),
+    }
+}
+
+fn validate_keycloak(config: &KeycloakConfig, problems: &mut Vec<String>) {
+    check_url("keycloak.url", &config.url, config.allow_http, problems);
+    if let Some(admin_url) = &config.admin_url {
+        check_url("keycloak.admin_url", admin_url, config.allow_http, problems);
+    }
+    if let Some(issuer) = &config.issuer {
+        check_url("keycloak.issuer", issuer, config.allow_http, problems);
+    }
+    for url in &config.fallback_urls {
+        check_url("keycloak.fallback_urls", url, config.allow_http, problems);
+    }
+    // failover replaces url in request URLs, other endpoints would not fail over
+    if !config.fallback_urls.is_empty() {
+        if config.admin_url.is_some() {
+            problems
+                .push("keycloak.fallback_urls cannot be used with keycloak.admin_url".to_string());
+        }
+        if config.discovery {
+            problems
+                .push("keycloak.fallback_urls cannot be used with keycloak.discovery".to_string());
+        }
+    }
+    for (key, value) in [("realm", &config.realm), ("client_id", &config.client_id)] {
+        if value.trim().is_empty() {
+            problems.push(format!("keycloak.{} must not be empty", key));
+        }
+    }
+    let client_secret = [
+        (!config.client_secret.trim().is_empty()).then_some("client_secret"),
+        config
+            .client_secret_file
+            .as_ref()
+            .map(|_| "client_secret_file"),
+        config
+            .client_secret_env
+            .as_ref()
+            .map(|_| "client_secret_env"),
+    ];
+    let password = [
+        config.password.as_ref().map(|_| "password"),
+        config.password_file.as_ref().map(|_| "password_file"),
+        config.password_env.as_ref().map(|_| "password_env"),
+    ];
+    let uses_secret = config.auth_method == AuthMethod::ClientSecret;
+    check_secret_sources("client_secret", &client_secret, uses_secret, problems);
+    if config.auth_method == AuthMethod::PrivateKeyJwt && config.private_key_file.is_none() {
+        problems.push(
+            "keycloak.auth_method is \"private_key_jwt\", but keycloak.private_key_file is missing"
+                .to_string(),
+        );
+    }
+    match (&config.tls_client_cert, &config.tls_client_key) {
+        (Some(_), None) => problems.push(
+            "keycloak.tls_client_cert is set, but keycloak.tls_client_key is missing".to_string(),
+        ),
+        (None, Some(_)) => problems.push(
+            "keycloak.tls_client_key is set, but keycloak.tls_client_cert is missing".to_string(),
+        ),
+        (None, None) if config.auth_method == AuthMethod::TlsClientAuth => problems.push(
+            "keycloak.auth_method is \"tls_client_auth\", but keycloak.tls_client_cert and keycloak.tls_client_key are missing"
+                .to_string(),
+        ),
+        _ => {}
+    }
+    check_secret_sources("password", &password, config.username.is_some(), problems);
+    if config.username.is_none() && password.iter().any(Option::is_some) {
+        problems.push("keycloak.password is set, but keycloak.username is missing".to_string());
+    }
+    match (&config.proxy, config.no_proxy.is_empty()) {
+        (Some(proxy), _) => match Url::parse(proxy) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            Ok(url) => problems.push(format!(
+                "keycloak.proxy '{}' has unsupported scheme '{}', use http:// or https://",
+                proxy,
+                url.scheme()
+            )),
+            Err(err) => problems.push(format!(
+                "keycloak.proxy '{}' is not a valid URL: {}",
+                proxy, err
+            )),
+        },
+        (None, false) => {
+            problems.push("keycloak.no_proxy is set, but keycloak.proxy is missing".to_string())
+        }
+        (None, true) => {}
+    }
+    if let Some(fingerprint) = &config.tls_pin_sha256 {
+        if let Err(err) = parse_fingerprint(fingerprint) {
+            problems.push(format!("keycloak.tls_pin_sha256 {}", err));
+        }
+    }
+    if config.tls_insecure && (config.ca_file.is_some() || config.tls_pin_sha256.is_some()) {
+        problems.push(
+            "keycloak.tls_insecure disables the checks of keycloak.ca_file and keycloak.tls_pin_sha256"
+                .to_string(),
+        );
+    }
+    for (key, value) in [
+        ("connect_timeout", config.connect_timeout),
+        ("request_timeout", config.request_timeout),
+        ("lookup_timeout", config.lookup_timeout),
+    ] {
+        if value == 0 {
+            problems.push(format!("keycloak.{} must be greater than 0", key));
+        }
+        check_max(&format!("keycloak.{}", key), value, MAX_TIMEOUT, problems);
+    }
+    check_max(
+        "keycloak.retry_backoff",
+        config.retry_backoff,
+        MAX_TIMEOUT * 1000,
+        problems,
+    );
+    for (key, value) in [
+        ("pool_idle_timeout", config.pool_idle_timeout),
+        ("failover_cooldown", config.failover_cooldown),
+        ("circuit_breaker_cooldown", config.circuit_breaker_cooldown),
+        ("bad_credentials_backoff", config.bad_credentials_backoff),
+    ] {
+        check_max(&format!("keycloak.{}", key), value, MAX_DURATION, problems);
+    }
+}
+
+fn validate_cache(config: &CacheConfig, problems: &mut Vec<String>) {
+    for (key, value) in [
+        ("positive_ttl", config.positive_ttl),
+        ("negative_ttl", config.negative_ttl),
+        ("max_staleness", config.max_staleness),
+        ("refresh_interval", config.refresh_interval),
+    ] {
+        check_max(&format!("cache.{}", key), value, MAX_DURATION, problems);
+    }
+}
+
+fn validate_mapping(config: &MappingConfig, problems: &mut Vec<String>) {
+    // all user fields are read from the attributes of the same user,
+    // so each of them needs its own attribute
+    let user_attributes = [
+        ("user_home", Some(&config.user_home)),
+        ("user_shell", Some(&config.user_shell)),
+        ("user_gecos", Some(&config.user_gecos)),
+        ("user_uid", Some(&config.user_uid)),
+        ("user_gid", Some(&config.user_gid)),
+        ("shadow_last_change", config.shadow_last_change.as_ref()),
+        ("shadow_min_days", config.shadow_min_days.as_ref()),
+        ("shadow_max_days", config.shadow_max_days.as_ref()),
+        ("shadow_warn_days", config.shadow_warn_days.as_ref()),
+        ("shadow_inactive_days", config.shadow_inactive_days.as_ref()),
+        ("shadow_expire", config.shadow_expire.as_ref()),
+    ];
+    let mut keys_by_attribute: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (key, attribute) in user_attributes {
+        if let Some(attribute) = attribute {
+            if attribute.trim().is_empty() {
+                problems.push(format!("mapping.{} must not be empty", key));
+            } else {
+                keys_by_attribute.entry(attribute).or_default().push(key);
+            }
+        }
+    }
+    for (attribute, keys) in keys_by_attribute {
+        if keys.len() > 1 {
+            problems.push(format!(
+                "mapping.{} all map to the attribute '{}', each needs its own attribute",
+                keys.join(", mapping."),
+                attribute
+            ));
+        }
+    }
+    if config.group_gid.trim().is_empty() {
+        problems.push("mapping.group_gid must not be empty".to_string());
+    }
+    // these become part of group names, which are separated by ':' and ',' in NSS
+    for (key, value) in [
+        ("group_path_separator", &config.group_path_separator),
+        ("group_name_prefix", &config.group_name_prefix),
+    ] {
+        if value.contains([':', ',']) || value.contains(char::is_whitespace) {
+            problems.push(format!(
+                "mapping.{} '{}' must not contain ':', ',' or whitespace",
+                key, value
+            ));
+        }
+    }
+}
+
+impl Config {
+    /// Check the configuration for values that deserialize but cannot work.
+    /// Returns an error listing all problems found.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        validate_keycloak(&self.keycloak, &mut problems);
+        validate_mapping(&self.mapping, &mut problems);
+        validate_cache(&self.cache, &mut problems);
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "Invalid configuration:\n  - {}",
+                problems.join("\n  - ")
+            ))
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// --- Unit tests -------------------------------------------------------------------------------
+// -----------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    fn problems(config: Config) -> String {
+        config.validate().unwrap_err().to_string()
+    }
+
+    /// Test that only HTTPS, or HTTP to localhost or when explicitly allowed, is accepted
+    #[test]
+    fn test_validate_url() {
+        assert!(test_config(r#"url = "https://sso.example.com""#, "")
+            .validate()
+            .is_ok());
+        assert!(test_config(r#"url = "http://localhost:8080""#, "")
+            .validate()
+            .is_ok());
+        assert!(test_config(r#"url = "http://[::1]:8080""#, "")
+            .validate()
+            .is_ok());
+        assert!(
+            test_config("url = \"http://keycloak:8080\"\nallow_http = true", "")
+                .validate()
+                .is_ok()
+        );
+        assert!(problems(test_config(r#"url = "http://keycloak:8080""#, "")).contains("plain HTTP"));
+        assert!(problems(test_config(r#"url = "ftp://keycloak""#, "")).contains("scheme 'ftp'"));
+        assert!(problems(test_config(r#"url = "sso.example.com/auth""#, ""))
+            .contains("not a valid URL"));
+        let fallback = problems(test_config(
+            "url = \"https://sso1.example.com\"\nfallback_urls = [\"http://sso2.example.com\"]",
+            "",
+        ));
+        assert!(
+            fallback.contains("keycloak.fallback_urls 'http://sso2.example.com' uses plain HTTP")
+        );
+        let admin = problems(test_config(
+            "url = \"https://sso.example.com\"\nadmin_url = \"admin.example.com\"",
+            "",
+        ));
+        assert!(admin.contains("keycloak.admin_url 'admin.example.com' is not a valid URL"));
+    }
+
+    /// Test that failover is rejected with endpoints that are not served at `url`
+    #[test]
+    fn test_validate_fallback_urls() {
+        let fallback =
+            "url = \"https://sso1.example.com\"\nfallback_urls = [\"https://sso2.example.com\"]";
+        assert!(test_config(fallback, "").validate().is_ok());
+        let admin = problems(test_config(
+            &format!("{}\nadmin_url = \"https://admin.example.com\"", fallback),
+            "",
+        ));
+        assert!(admin.contains("keycloak.fallback_urls cannot be used with keycloak.admin_url"));
+        let discovery = problems(test_config(&format!("{}\ndiscovery = true", fallback), ""));
+        assert!(discovery.contains("keycloak.fallback_urls cannot be used with keycloak.discovery"));
+    }
+
+    /// Test that durations beyond their upper limit are rejected, as computing
+    /// deadlines and expiration times from them would overflow
+    #[test]
+    fn test_validate_limits() {
+        let url = r#"url = "https://sso.example.com""#;
+        assert!(test_config(url, "[cache]\npositive_ttl = 31622400")
+            .validate()
+            .is_ok());
+        let cache = problems(test_config(
+            url,
+            "[cache]\npositive_ttl = 9223372036854775807",
+        ));
+        assert!(cache.contains("cache.positive_ttl must be at most 31622400"));
+        let keycloak = problems(test_config(
+            &format!("{}\nlookup_timeout = 9223372036854775807", url),
+            "",
+        ));
+        assert!(keycloak.contains("keycloak.lookup_timeout must be at most 3600"));
+    }
+
+    /// Test that incomplete credentials are rejected
+    #[test]
+    fn test_validate_credentials() {
+        let url = r#"url = "https://sso.example.com""#;
+        let missing = problems(test_config(
+            &format!("{}\nusername = \"nss-user\"", url),
+            "",
+        ));
+        assert!(missing.contains("keycloak.password is missing"));
+
+        let ambiguous = problems(test_config(
+            &format!("{}\nclient_secret_file = \"/etc/nss-keycloak/secret\"", url),
+            "",
+        ));
+        assert!(
+            ambiguous.contains("keycloak.client_secret, keycloak.client_secret_file are all set")
+        );
+
+        let without_key = problems(test_config(
+            &format!("{}\nauth_method = \"private_key_jwt\"", url),
+            "",
+        ));
+        assert!(without_key.contains("keycloak.private_key_file is missing"));
+        assert!(test_config(
+            &format!(
+                "{}\nauth_method = \"private_key_jwt\"\nprivate_key_file = \"/etc/nss-keycloak/key.pem\"",
+                url
+            ),
+            "",
+        )
+        .validate()
+        .is_ok());
+
+        let without_cert = problems(test_config(
+            &format!("{}\nauth_method = \"tls_client_auth\"", url),
+            "",
+        ));
+        assert!(without_cert
+            .contains("keycloak.tls_client_cert and keycloak.tls_client_key are missing"));
+        let without_key = problems(test_config(
+            &format!("{}\ntls_client_cert = \"/etc/ssl/host.pem\"", url),
+            "",
+        ));
+        assert!(without_key.contains("keycloak.tls_client_key is missing"));
+    }
+
+    /// Test that invalid pins and conflicting TLS settings are rejected
+    #[test]
+    fn test_validate_tls() {
+        let url = r#"url = "https://sso.example.com""#;
+        let pin = "AB:".repeat(31) + "AB";
+        assert!(
+            test_config(&format!("{}\ntls_pin_sha256 = \"{}\"", url, pin), "")
+                .validate()
+                .is_ok()
+        );
+        let invalid = problems(test_config(
+            &format!("{}\ntls_pin_sha256 = \"AB:CD\"", url),
+            "",
+        ));
+        assert!(invalid.contains("keycloak.tls_pin_sha256 'AB:CD' is not a SHA-256 fingerprint"));
+        let conflicting = problems(test_config(
+            &format!("{}\ntls_insecure = true\ntls_pin_sha256 = \"{}\"", url, pin),
+            "",
+        ));
+        assert!(conflicting.contains("keycloak.tls_insecure disables the checks"));
+    }
+
+    /// Test that the proxy must be an HTTP URL and no_proxy requires a proxy
+    #[test]
+    fn test_validate_proxy() {
+        let url = r#"url = "https://sso.example.com""#;
+        assert!(test_config(
+            &format!(
+                "{}\nproxy = \"http://proxy:3128\"\nno_proxy = [\"localhost\"]",
+                url
+            ),
+            ""
+        )
+        .validate()
+        .is_ok());
+        let scheme = problems(test_config(
+            &format!("{}\nproxy = \"ftp://proxy\"", url),
+            "",
+        ));
+        assert!(scheme.contains("unsupported scheme 'ftp'"));
+        let without_proxy = problems(test_config(
+            &format!("{}\nno_proxy = [\"localhost\"]", url),
+            "",
+        ));
+        assert!(without_proxy.contains("keycloak.proxy is missing"));
+    }
+
+    /// Test that user fields mapped to the same attribute are reported
+    #[test]
+    fn test_validate_duplicate_mapping() {
+        let problems = problems(test_config(
+            r#"url = "https://sso.example.com""#,
+            r#"shadow_expire = "uidnumber""#,
+        ));
+        assert!(problems.contains("mapping.user_uid, mapping.shadow_expire"));
+    }
+
+    /// Test that unknown keys are rejected
+    #[test]
+    fn test_unknown_keys() {
+        let result = toml::from_str::<Config>(
+            r#"
+            [keycloak]
+            realm = "myrealm"
+            client_id = "myclient"
+            client_secret = "mysecret"
+            url = "https://sso.example.com"
+            timeout = 5
+
+            [mapping]
+            user_home = "homedirectory"
+            user_shell = "loginshell"
+            user_gecos = "gecos"
+            user_uid = "uidnumber"
+            user_gid = "gidnumber"
+            group_gid = "gidnumber"
+            "#,
+        );
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("unknown field `timeout`"));
+    }
+
+    /// Test that fingerprints are accepted with and without colons
+    #[test]
+    fn test_parse_fingerprint() {
+        let hex = "0123456789abcdef".repeat(4);
+        let expected = parse_fingerprint(&hex).unwrap();
+        assert_eq!(expected.len(), 32);
+        assert_eq!(expected[..2], [0x01, 0x23]);
+        let with_colons: Vec<String> = hex
+            .to_uppercase()
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| String::from_utf8(pair.to_vec()).unwrap())
+            .collect();
+        assert_eq!(parse_fingerprint(&with_colons.join(":")).unwrap(), expected);
+        assert!(parse_fingerprint("0123").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+    }
+}
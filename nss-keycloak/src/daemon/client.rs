@@ -0,0 +1,63 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::Result;
+use libnss::interop::Response;
+
+use super::{to_response, Reply, Request, Wire};
+
+// time for the daemon to answer beyond the lookup timeout it applies itself
+const REPLY_MARGIN: Duration = Duration::from_secs(5);
+
+// read from the configuration file on first use
+static REPLY_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// Time to wait for a reply, which only guards against a hung daemon.
+/// The daemon limits each lookup to the lookup timeout of the configuration.
+/// Only that setting is read, as the clients do not need the secrets.
+fn reply_timeout() -> Duration {
+    *REPLY_TIMEOUT.get_or_init(|| {
+        Duration::from_secs(crate::config::load_lookup_timeout()).saturating_add(REPLY_MARGIN)
+    })
+}
+
+/// Send a request to the daemon listening on the given socket and wait for its reply.
+/// Returns None if the daemon is not running.
+/// Returns Response::Unavail if the daemon fails to answer.
+pub fn query<T: Wire>(socket_path: &str, request: &Request) -> Option<Response<T>> {
+    let stream = match UnixStream::connect(socket_path) {
+        Ok(stream) => stream,
+        Err(err) => {
+            log::debug!("Resolver daemon not available, resolving directly: {}", err);
+            return None;
+        }
+    };
+    match exchange(stream, request) {
+        Ok(reply) => Some(to_response(reply)),
+        Err(err) => {
+            log::error!(
+                "Failed to query resolver daemon for {:?}: {:?}",
+                request,
+                err
+            );
+            Some(Response::Unavail)
+        }
+    }
+}
+
+fn exchange<M: serde::de::DeserializeOwned>(
+    mut stream: UnixStream,
+    request: &Request,
+) -> Result<Reply<M>> {
+    let timeout = reply_timeout();
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
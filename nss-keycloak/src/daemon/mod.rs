@@ -0,0 +1,219 @@
+mod client;
+mod server;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libnss::group::Group;
+use libnss::interop::Response;
+use libnss::passwd::Passwd;
+use libnss::shadow::Shadow;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+pub use client::query;
+pub use server::serve;
+
+pub const SOCKET_ENV: &str = "NSSKEYCLOAK_SOCKET";
+const SOCKET_DEFAULT_PATH: &str = "/run/nss-keycloak/nss-keycloakd.sock";
+
+// set in the daemon itself, which resolves all lookups directly
+static SERVING: AtomicBool = AtomicBool::new(false);
+
+/// Get the path of the daemon socket from the environment variable or use the default
+/// socket path. ENV is `NSSKEYCLOAK_SOCKET` and the default is
+/// `/run/nss-keycloak/nss-keycloakd.sock`.
+pub fn get_socket_path() -> String {
+    std::env::var(SOCKET_ENV).unwrap_or(SOCKET_DEFAULT_PATH.to_string())
+}
+
+/// Lookup requested from the daemon, one per connection
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "lookup", content = "key", rename_all = "snake_case")]
+pub enum Request {
+    AllUsers,
+    UserByName(String),
+    UserByUid(libc::uid_t),
+    AllGroups,
+    GroupByName(String),
+    GroupByGid(libc::gid_t),
+    AllShadow,
+    ShadowByName(String),
+    GroupsByUser(String),
+}
+
+/// Reply of the daemon, mirroring the NSS response of the lookup
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status", content = "result", rename_all = "snake_case")]
+enum Reply<T> {
+    Success(T),
+    NotFound,
+    Unavail,
+    TryAgain,
+    Return,
+}
+
+/// An NSS entry that can be sent over the daemon socket
+pub trait Wire: Sized {
+    type Message: Serialize + DeserializeOwned;
+
+    fn to_message(&self) -> Self::Message;
+    fn from_message(message: Self::Message) -> Self;
+}
+
+impl<T: Wire> Wire for Vec<T> {
+    type Message = Vec<T::Message>;
+
+    fn to_message(&self) -> Self::Message {
+        self.iter().map(T::to_message).collect()
+    }
+
+    fn from_message(message: Self::Message) -> Self {
+        message.into_iter().map(T::from_message).collect()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PasswdMessage {
+    name: String,
+    uid: u32,
+    gid: u32,
+    gecos: String,
+    dir: String,
+    shell: String,
+}
+
+impl Wire for Passwd {
+    type Message = PasswdMessage;
+
+    fn to_message(&self) -> PasswdMessage {
+        PasswdMessage {
+            name: self.name.clone(),
+            uid: self.uid,
+            gid: self.gid,
+            gecos: self.gecos.clone(),
+            dir: self.dir.clone(),
+            shell: self.shell.clone(),
+        }
+    }
+
+    fn from_message(message: PasswdMessage) -> Self {
+        Passwd {
+            name: message.name,
+            passwd: "x".to_string(),
+            uid: message.uid,
+            gid: message.gid,
+            gecos: message.gecos,
+            dir: message.dir,
+            shell: message.shell,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GroupMessage {
+    name: String,
+    gid: u32,
+    members: Vec<String>,
+}
+
+impl Wire for Group {
+    type Message = GroupMessage;
+
+    fn to_message(&self) -> GroupMessage {
+        GroupMessage {
+            name: self.name.clone(),
+            gid: self.gid,
+            members: self.members.clone(),
+        }
+    }
+
+    fn from_message(message: GroupMessage) -> Self {
+        Group {
+            name: message.name,
+            passwd: "x".to_string(),
+            gid: message.gid,
+            members: message.members,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ShadowMessage {
+    name: String,
+    passwd: String,
+    last_change: isize,
+    change_min_days: isize,
+    change_max_days: isize,
+    change_warn_days: isize,
+    change_inactive_days: isize,
+    expire_date: isize,
+}
+
+impl Wire for Shadow {
+    type Message = ShadowMessage;
+
+    fn to_message(&self) -> ShadowMessage {
+        ShadowMessage {
+            name: self.name.clone(),
+            passwd: self.passwd.clone(),
+            last_change: self.last_change,
+            change_min_days: self.change_min_days,
+            change_max_days: self.change_max_days,
+            change_warn_days: self.change_warn_days,
+            change_inactive_days: self.change_inactive_days,
+            expire_date: self.expire_date,
+        }
+    }
+
+    fn from_message(message: ShadowMessage) -> Self {
+        Shadow {
+            name: message.name,
+            passwd: message.passwd,
+            last_change: message.last_change,
+            change_min_days: message.change_min_days,
+            change_max_days: message.change_max_days,
+            change_warn_days: message.change_warn_days,
+            change_inactive_days: message.change_inactive_days,
+            expire_date: message.expire_date,
+            reserved: 0,
+        }
+    }
+}
+
+fn to_reply<T: Wire>(response: Response<T>) -> Reply<T::Message> {
+    match response {
+        Response::Success(entry) => Reply::Success(entry.to_message()),
+        Response::NotFound => Reply::NotFound,
+        Response::Unavail => Reply::Unavail,
+        Response::TryAgain => Reply::TryAgain,
+        Response::Return => Reply::Return,
+    }
+}
+
+fn to_response<T: Wire>(reply: Reply<T::Message>) -> Response<T> {
+    match reply {
+        Reply::Success(message) => Response::Success(T::from_message(message)),
+        Reply::NotFound => Response::NotFound,
+        Reply::Unavail => Response::Unavail,
+        Reply::TryAgain => Response::TryAgain,
+        Reply::Return => Response::Return,
+    }
+}
+
+/// Resolve a lookup of an NSS hook through the daemon, or with `direct` if the
+/// daemon is not running. The daemon itself always resolves lookups directly.
+/// A panic anywhere in the lookup is turned into Response::Unavail.
+pub(crate) fn resolve<T: Wire>(
+    request: Request,
+    direct: impl FnOnce() -> Response<T>,
+) -> Response<T> {
+    crate::catch_panic(&request, || {
+        if SERVING.load(Ordering::Relaxed) {
+            return direct();
+        }
+        match query(&get_socket_path(), &request) {
+            Some(response) => response,
+            None => direct(),
+        }
+    })
+}
@@ -0,0 +1,253 @@
+use std::fs::{self, DirBuilder, Permissions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use libnss::group::GroupHooks;
+use libnss::initgroups::InitgroupsHooks;
+use libnss::interop::Response;
+use libnss::passwd::PasswdHooks;
+use libnss::shadow::ShadowHooks;
+
+use super::{to_reply, Request, Wire, SERVING};
+use crate::CONFIG;
+use crate::{KeycloakNssGroup, KeycloakNssInitgroups, KeycloakNssPasswd, KeycloakNssShadow};
+
+// limits for reading a request, so that idle clients cannot hold on to a thread.
+// The timeout is for the whole request, not for each read.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_SIZE: u64 = 4096;
+
+// connections handled at the same time, further connections are closed right away
+static CONNECTIONS: ConnectionSlots = ConnectionSlots::new(64);
+
+/// Limits the number of connections handled at the same time, as any local user
+/// can open connections to the socket
+struct ConnectionSlots {
+    limit: usize,
+    active: Mutex<usize>,
+}
+
+/// A connection being handled, which frees its slot when dropped
+struct ConnectionSlot<'a>(&'a ConnectionSlots);
+
+impl ConnectionSlots {
+    const fn new(limit: usize) -> ConnectionSlots {
+        ConnectionSlots {
+            limit,
+            active: Mutex::new(0),
+        }
+    }
+
+    /// Take a free slot, or None if all slots are taken. Never waits, so that
+    /// clients holding all slots cannot stop the daemon from accepting connections.
+    fn try_acquire(&self) -> Option<ConnectionSlot<'_>> {
+        let mut active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        if *active >= self.limit {
+            return None;
+        }
+        *active += 1;
+        Some(ConnectionSlot(self))
+    }
+}
+
+impl Drop for ConnectionSlot<'_> {
+    fn drop(&mut self) {
+        let mut active = self.0.active.lock().unwrap_or_else(PoisonError::into_inner);
+        *active -= 1;
+    }
+}
+
+/// Reads from a stream until a deadline, however the reads are spread over time
+struct DeadlineReader<'a> {
+    stream: &'a UnixStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Listen for requests on the given socket and resolve them directly, sharing the
+/// Keycloak session and cache of this process between all clients.
+/// Each connection is handled in its own thread, up to a limit of connections at the
+/// same time; further connections are closed, so that their lookups fail right away
+/// instead of waiting. Only returns on errors of the socket.
+pub fn serve(socket_path: &str) -> Result<()> {
+    SERVING.store(true, Ordering::Relaxed);
+    let path = Path::new(socket_path);
+    if let Some(parent) = path.parent() {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o755)
+            .create(parent)?;
+    }
+    // remove the socket of a previous run
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind socket {}", socket_path))?;
+    // like the passwd and group files, lookups are open to all local users
+    fs::set_permissions(path, Permissions::from_mode(0o666))?;
+    log::info!("Listening on {}", socket_path);
+    if let Ok(config) = CONFIG.as_ref() {
+        if config.cache.directory.is_some() {
+            let interval = Duration::from_secs(config.cache.refresh_interval);
+            std::thread::spawn(move || refresh_persistent_cache(interval));
+        }
+    }
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let Some(slot) = CONNECTIONS.try_acquire() else {
+            log::debug!("Too many connections, closing a new one");
+            continue;
+        };
+        std::thread::spawn(move || {
+            let _slot = slot;
+            if let Err(err) = handle(stream) {
+                log::warn!("Failed to handle request: {:?}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Enumerate all users and groups periodically, which replaces the persistent cache.
+/// Lookups of single entries do not write it, as rewriting the whole file for every
+/// lookup is too expensive. Failed enumerations are logged by the lookup itself.
+fn refresh_persistent_cache(interval: Duration) {
+    loop {
+        KeycloakNssPasswd::get_all_entries();
+        KeycloakNssGroup::get_all_entries();
+        std::thread::sleep(interval);
+    }
+}
+
+/// Read the request line of a client, which must arrive within the timeout
+fn read_request(stream: &UnixStream, timeout: Duration) -> Result<Request> {
+    let reader = DeadlineReader {
+        stream,
+        deadline: Instant::now() + timeout,
+    };
+    let mut line = String::new();
+    BufReader::new(reader.take(MAX_REQUEST_SIZE)).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Read a request from a client, resolve it and write the reply
+fn handle(mut stream: UnixStream) -> Result<()> {
+    let request = read_request(&stream, REQUEST_TIMEOUT)?;
+    let mut reply = match request {
+        Request::AllUsers => encode(KeycloakNssPasswd::get_all_entries())?,
+        Request::UserByName(name) => encode(KeycloakNssPasswd::get_entry_by_name(name))?,
+        Request::UserByUid(uid) => encode(KeycloakNssPasswd::get_entry_by_uid(uid))?,
+        Request::AllGroups => encode(KeycloakNssGroup::get_all_entries())?,
+        Request::GroupByName(name) => encode(KeycloakNssGroup::get_entry_by_name(name))?,
+        Request::GroupByGid(gid) => encode(KeycloakNssGroup::get_entry_by_gid(gid))?,
+        Request::GroupsByUser(user) => encode(KeycloakNssInitgroups::get_entries_by_user(user))?,
+        // the shadow database is only readable by root
+        Request::AllShadow if is_root(&stream)? => encode(KeycloakNssShadow::get_all_entries())?,
+        Request::ShadowByName(name) if is_root(&stream)? => {
+            encode(KeycloakNssShadow::get_entry_by_name(name))?
+        }
+        Request::AllShadow | Request::ShadowByName(_) => {
+            encode::<libnss::shadow::Shadow>(Response::Unavail)?
+        }
+    };
+    reply.push('\n');
+    stream.write_all(reply.as_bytes())?;
+    Ok(())
+}
+
+fn encode<T: Wire>(response: Response<T>) -> Result<String> {
+    Ok(serde_json::to_string(&to_reply(response))?)
+}
+
+/// Check if the peer of a connection runs as root
+fn is_root(stream: &UnixStream) -> Result<bool> {
+    let mut credentials = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: credentials and length point to valid memory of the given size
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to get peer credentials");
+    }
+    Ok(credentials.uid == 0)
+}
+
+// -----------------------------------------------------------------------------------------------
+// --- Unit tests -------------------------------------------------------------------------------
+// -----------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that a slot is only available again after a connection has finished
+    #[test]
+    fn test_connection_slots() {
+        static SLOTS: ConnectionSlots = ConnectionSlots::new(2);
+        let first = SLOTS.try_acquire().unwrap();
+        let _second = SLOTS.try_acquire().unwrap();
+        assert!(SLOTS.try_acquire().is_none());
+        drop(first);
+        assert!(SLOTS.try_acquire().is_some());
+        assert_eq!(*SLOTS.active.lock().unwrap(), 1);
+    }
+
+    /// Test that a client sending its request slowly is dropped after the timeout
+    /// for the whole request, although every single read is fast enough
+    #[test]
+    fn test_read_request_timeout() {
+        let request = serde_json::to_string(&Request::AllUsers).unwrap();
+        let (server, mut client) = UnixStream::pair().unwrap();
+        let slow_request = request.clone();
+        let writer = std::thread::spawn(move || {
+            for byte in slow_request.as_bytes() {
+                if client.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        });
+        let start = Instant::now();
+        assert!(read_request(&server, Duration::from_millis(100)).is_err());
+        assert!(start.elapsed() < Duration::from_millis(150));
+        drop(server);
+        writer.join().unwrap();
+
+        let (server, mut client) = UnixStream::pair().unwrap();
+        client
+            .write_all(format!("{}\n", request).as_bytes())
+            .unwrap();
+        assert!(matches!(
+            read_request(&server, Duration::from_millis(100)).unwrap(),
+            Request::AllUsers
+        ));
+    }
+}
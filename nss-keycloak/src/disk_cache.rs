@@ -0,0 +1,368 @@
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(feature = "mock")]
+use mock_instant::SystemTime;
+#[cfg(not(feature = "mock"))]
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+use libnss::group::Group;
+use libnss::passwd::Passwd;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::{Entry, Key};
+use crate::config::CacheConfig;
+
+// distinguishes the temporary files of concurrent writes within a process
+static WRITE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// An NSS entry that can be stored in the persistent cache
+pub trait Persistent: Entry {
+    /// name of the cache file in the cache directory
+    const FILE_NAME: &'static str;
+    type Stored: Serialize + DeserializeOwned;
+
+    fn to_stored(&self) -> Self::Stored;
+    fn from_stored(stored: Self::Stored) -> Self;
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StoredPasswd {
+    name: String,
+    uid: u32,
+    gid: u32,
+    gecos: String,
+    dir: String,
+    shell: String,
+}
+
+impl Persistent for Passwd {
+    const FILE_NAME: &'static str = "passwd.json";
+    type Stored = StoredPasswd;
+
+    fn to_stored(&self) -> StoredPasswd {
+        StoredPasswd {
+            name: self.name.clone(),
+            uid: self.uid,
+            gid: self.gid,
+            gecos: self.gecos.clone(),
+            dir: self.dir.clone(),
+            shell: self.shell.clone(),
+        }
+    }
+
+    fn from_stored(stored: StoredPasswd) -> Self {
+        Passwd {
+            name: stored.name,
+            passwd: "x".to_string(),
+            uid: stored.uid,
+            gid: stored.gid,
+            gecos: stored.gecos,
+            dir: stored.dir,
+            shell: stored.shell,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StoredGroup {
+    name: String,
+    gid: u32,
+    members: Vec<String>,
+}
+
+impl Persistent for Group {
+    const FILE_NAME: &'static str = "group.json";
+    type Stored = StoredGroup;
+
+    fn to_stored(&self) -> StoredGroup {
+        StoredGroup {
+            name: self.name.clone(),
+            gid: self.gid,
+            members: self.members.clone(),
+        }
+    }
+
+    fn from_stored(stored: StoredGroup) -> Self {
+        Group {
+            name: stored.name,
+            passwd: "x".to_string(),
+            gid: stored.gid,
+            members: stored.members,
+        }
+    }
+}
+
+/// An entry of a cache file together with the time of its last update
+#[derive(Serialize, Deserialize)]
+struct StoredEntry<S> {
+    updated: u64,
+    #[serde(flatten)]
+    entry: S,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn matches_key<T: Entry>(entry: &T, key: Key) -> bool {
+    match key {
+        Key::Name(name) => entry.name() == name,
+        Key::Id(id) => entry.id() == id,
+    }
+}
+
+/// Owner of the cache files: root, which runs nss-keycloakd and the privileged
+/// processes writing the cache
+#[cfg(not(test))]
+fn cache_owner() -> u32 {
+    0
+}
+
+/// Owner of the cache files in unit tests, which do not necessarily run as root
+#[cfg(test)]
+fn cache_owner() -> u32 {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() }
+}
+
+/// Read a file of the cache directory, or None if it does not exist.
+/// Files that are not owned by root or accessible by other users are rejected,
+/// as they might have been written by someone else than this plugin.
+fn read_file(directory: &str, file_name: &str) -> Result<Option<String>> {
+    let path = Path::new(directory).join(file_name);
+    match fs::metadata(&path) {
+        Ok(metadata) if metadata.uid() != cache_owner() || metadata.mode() & 0o077 != 0 => Err(
+            anyhow!("{} must be owned by root with mode 0600", path.display()),
+        ),
+        Ok(_) => Ok(Some(fs::read_to_string(&path)?)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Replace a file of the cache directory atomically, readable only by its owner
+fn write_file(directory: &str, file_name: &str, contents: &str) -> Result<()> {
+    let directory = Path::new(directory);
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(directory)?;
+    let tmp_path = directory.join(format!(
+        ".{}.{}.{}",
+        file_name,
+        std::process::id(),
+        WRITE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let result = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, directory.join(file_name))?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Read all entries of a cache file, or no entries if it does not exist
+fn read_entries<T: Persistent>(directory: &str) -> Result<Vec<(u64, T)>> {
+    let Some(buf) = read_file(directory, T::FILE_NAME)? else {
+        return Ok(vec![]);
+    };
+    let entries: Vec<StoredEntry<T::Stored>> = serde_json::from_str(&buf)?;
+    Ok(entries
+        .into_iter()
+        .map(|stored| (stored.updated, T::from_stored(stored.entry)))
+        .collect())
+}
+
+/// Replace a cache file with the given entries
+fn write_entries<T: Persistent>(directory: &str, entries: &[(u64, T)]) -> Result<()> {
+    let entries: Vec<StoredEntry<T::Stored>> = entries
+        .iter()
+        .map(|(updated, entry)| StoredEntry {
+            updated: *updated,
+            entry: entry.to_stored(),
+        })
+        .collect();
+    write_file(directory, T::FILE_NAME, &serde_json::to_string(&entries)?)
+}
+
+/// Read a state file that is shared by all processes of the host through the cache
+/// directory, or None if it does not exist
+pub fn read_state<S: DeserializeOwned>(directory: &str, file_name: &str) -> Result<Option<S>> {
+    match read_file(directory, file_name)? {
+        Some(buf) => Ok(Some(serde_json::from_str(&buf)?)),
+        None => Ok(None),
+    }
+}
+
+/// Replace a state file of the cache directory
+pub fn write_state<S: Serialize>(directory: &str, file_name: &str, state: &S) -> Result<()> {
+    write_file(directory, file_name, &serde_json::to_string(state)?)
+}
+
+/// Remove a state file of the cache directory, if it exists
+pub fn remove_state(directory: &str, file_name: &str) -> Result<()> {
+    match fs::remove_file(Path::new(directory).join(file_name)) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Get an entry from the persistent cache, if it is not older than the maximum staleness
+pub fn get<T: Persistent>(config: &CacheConfig, key: Key) -> Option<T> {
+    let directory = config.directory.as_ref()?;
+    let oldest = now().saturating_sub(config.max_staleness);
+    match read_entries::<T>(directory) {
+        Ok(entries) => entries
+            .into_iter()
+            .find(|(updated, entry)| *updated >= oldest && matches_key(entry, key))
+            .map(|(_, entry)| entry),
+        Err(err) => {
+            log::warn!("Failed to read persistent cache: {:?}", err);
+            None
+        }
+    }
+}
+
+/// Get all entries from the persistent cache that are not older than the maximum staleness.
+/// Returns None if there are no such entries.
+pub fn get_all<T: Persistent>(config: &CacheConfig) -> Option<Vec<T>> {
+    let directory = config.directory.as_ref()?;
+    let oldest = now().saturating_sub(config.max_staleness);
+    match read_entries::<T>(directory) {
+        Ok(entries) => {
+            let entries: Vec<T> = entries
+                .into_iter()
+                .filter(|(updated, _)| *updated >= oldest)
+                .map(|(_, entry)| entry)
+                .collect();
+            (!entries.is_empty()).then_some(entries)
+        }
+        Err(err) => {
+            log::warn!("Failed to read persistent cache: {:?}", err);
+            None
+        }
+    }
+}
+
+/// Replace the persistent cache with the result of an enumeration
+pub fn replace_all<T: Persistent>(config: &CacheConfig, entries: &[T]) {
+    let Some(directory) = config.directory.as_ref() else {
+        return;
+    };
+    let updated = now();
+    let entries: Vec<(u64, T)> = entries
+        .iter()
+        .map(|entry| (updated, entry.clone()))
+        .collect();
+    if let Err(err) = write_entries(directory, &entries) {
+        log::debug!("Failed to update persistent cache: {:?}", err);
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// --- Unit tests -------------------------------------------------------------------------------
+// -----------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn passwd(name: &str, uid: u32) -> Passwd {
+        Passwd {
+            name: name.to_string(),
+            passwd: "x".to_string(),
+            uid,
+            gid: 500,
+            gecos: ",,,".to_string(),
+            dir: format!("/home/{}", name),
+            shell: "/bin/bash".to_string(),
+        }
+    }
+
+    fn config(directory: &Path) -> CacheConfig {
+        CacheConfig {
+            directory: Some(directory.to_str().unwrap().to_string()),
+            ..CacheConfig::default()
+        }
+    }
+
+    /// Test that enumerations replace the cache file
+    #[test]
+    fn test_replace_all_and_get() {
+        let directory = tempfile::tempdir().unwrap();
+        let config = config(&directory.path().join("cache"));
+        assert!(get::<Passwd>(&config, Key::Name("user01")).is_none());
+
+        replace_all(&config, &[passwd("user01", 1000), passwd("user02", 1001)]);
+        replace_all(&config, &[passwd("user01", 1000), passwd("user03", 1002)]);
+
+        assert_eq!(
+            get::<Passwd>(&config, Key::Id(1000)).unwrap().name,
+            "user01"
+        );
+        assert_eq!(
+            get::<Passwd>(&config, Key::Name("user03")).unwrap().uid,
+            1002
+        );
+        assert!(get::<Passwd>(&config, Key::Name("user02")).is_none());
+        assert_eq!(get_all::<Passwd>(&config).unwrap().len(), 2);
+
+        let path = directory.path().join("cache").join("passwd.json");
+        assert_eq!(fs::metadata(path).unwrap().mode() & 0o777, 0o600);
+    }
+
+    /// Test that cache files accessible by other users are not served
+    #[test]
+    fn test_insecure_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let config = config(directory.path());
+        replace_all(&config, &[passwd("user01", 1000)]);
+        let path = directory.path().join("passwd.json");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(get::<Passwd>(&config, Key::Id(1000)).is_none());
+        let err = read_file(directory.path().to_str().unwrap(), "passwd.json").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("must be owned by root with mode 0600"));
+    }
+
+    /// Test that entries older than the maximum staleness are not served
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_max_staleness() {
+        use mock_instant::MockClock;
+        use std::time::Duration;
+
+        let directory = tempfile::tempdir().unwrap();
+        let config = CacheConfig {
+            max_staleness: 3600,
+            ..config(directory.path())
+        };
+        MockClock::set_system_time(Duration::from_secs(100_000));
+        replace_all(&config, &[passwd("user01", 1000)]);
+
+        MockClock::advance_system_time(Duration::from_secs(3600));
+        assert!(get::<Passwd>(&config, Key::Id(1000)).is_some());
+        MockClock::advance_system_time(Duration::from_secs(1));
+        assert!(get::<Passwd>(&config, Key::Id(1000)).is_none());
+        assert!(get_all::<Passwd>(&config).is_none());
+    }
+}
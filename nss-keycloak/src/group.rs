@@ -1,7 +1,8 @@
 use libnss::group::{Group, GroupHooks};
 use libnss::interop::Response;
 
-use crate::keycloak::auth::TokenProvider;
+use crate::cache::Key;
+use crate::daemon::Request;
 use crate::keycloak::groups::{get_group_by_gid, get_group_by_name, list_groups, KeycloakGroup};
 
 pub struct KeycloakNssGroup;
@@ -21,82 +22,66 @@ impl GroupHooks for KeycloakNssGroup {
     /// Get all groups from Keycloak
     /// calls keycloak::list_groups underneath
     fn get_all_entries() -> Response<Vec<Group>> {
-        let access_token = match crate::AUTH.lock().unwrap().get_access_token() {
-            Ok(token) => token.clone(),
-            Err(err) => {
-                log::error!("Failed to get access token: {:?}", err);
-                return Response::TryAgain;
-            }
-        };
-        match list_groups(
-            &crate::CONFIG.keycloak,
-            &crate::CONFIG.mapping,
-            &access_token,
-        ) {
-            Ok(groups) => {
-                Response::Success(groups.into_iter().map(|group| Group::from(group)).collect())
-            }
-            Err(err) => {
-                log::error!("Failed to get all groups: {:?}", err);
-                Response::TryAgain
-            }
-        }
+        crate::daemon::resolve(Request::AllGroups, || {
+            crate::cached_enumeration(
+                "get all groups",
+                &crate::CACHE.groups,
+                |config, client, access_token| {
+                    let groups =
+                        list_groups(&config.keycloak, &config.mapping, access_token, client)?;
+                    Ok(Some(groups.into_iter().map(Group::from).collect()))
+                },
+            )
+        })
     }
 
     /// Get a group by gid
     /// calls keycloak::get_group_by_gid underneath
     /// Returns Response::Success if group is found
     /// Returns Response::NotFound if group is not found
-    /// Returns Response::Unavail if there was an error
+    /// Returns Response::Unavail or Response::TryAgain if there was an error
     fn get_entry_by_gid(gid: libc::gid_t) -> Response<Group> {
-        let access_token = match crate::AUTH.lock().unwrap().get_access_token() {
-            Ok(token) => token.clone(),
-            Err(err) => {
-                log::error!("Failed to get access token: {:?}", err);
-                return Response::TryAgain;
-            }
-        };
-        match get_group_by_gid(
-            &crate::CONFIG.keycloak,
-            &crate::CONFIG.mapping,
-            &access_token,
-            gid,
-        ) {
-            Ok(None) => Response::NotFound,
-            Ok(Some(group)) => Response::Success(Group::from(group)),
-            Err(err) => {
-                log::error!("Failed to get group by gid: {:?}", err);
-                Response::TryAgain
-            }
-        }
+        crate::daemon::resolve(Request::GroupByGid(gid), || {
+            crate::cached_lookup(
+                "get group by gid",
+                &crate::CACHE.groups,
+                Key::Id(gid),
+                |config, client, access_token| {
+                    let group = get_group_by_gid(
+                        &config.keycloak,
+                        &config.mapping,
+                        access_token,
+                        gid,
+                        client,
+                    )?;
+                    Ok(group.map(Group::from))
+                },
+            )
+        })
     }
 
     /// Get a group by name
     /// calls keycloak::get_group_by_name underneath
     /// Returns Response::Success if group is found
     /// Returns Response::NotFound if group is not found
-    /// Returns Response::Unavail if there was an error
+    /// Returns Response::Unavail or Response::TryAgain if there was an error
     fn get_entry_by_name(name: String) -> Response<Group> {
-        let access_token = match crate::AUTH.lock().unwrap().get_access_token() {
-            Ok(token) => token.clone(),
-            Err(err) => {
-                log::error!("Failed to get access token: {:?}", err);
-                return Response::TryAgain;
-            }
-        };
-        let group = get_group_by_name(
-            &crate::CONFIG.keycloak,
-            &crate::CONFIG.mapping,
-            &access_token,
-            &name,
-        );
-        match group {
-            Err(err) => {
-                log::error!("Failed to get group by name: {:?}", err);
-                Response::TryAgain
-            }
-            Ok(None) => Response::NotFound,
-            Ok(Some(group)) => Response::Success(Group::from(group)),
-        }
+        crate::daemon::resolve(Request::GroupByName(name.clone()), || {
+            crate::cached_lookup(
+                "get group by name",
+                &crate::CACHE.groups,
+                Key::Name(&name),
+                |config, client, access_token| {
+                    let group = get_group_by_name(
+                        &config.keycloak,
+                        &config.mapping,
+                        access_token,
+                        &name,
+                        client,
+                    )?;
+                    Ok(group.map(Group::from))
+                },
+            )
+        })
     }
 }
@@ -0,0 +1,29 @@
+use libnss::group::Group;
+use libnss::initgroups::InitgroupsHooks;
+use libnss::interop::Response;
+
+use crate::daemon::Request;
+use crate::keycloak::groups::get_groups_by_user;
+
+pub struct KeycloakNssInitgroups;
+
+impl InitgroupsHooks for KeycloakNssInitgroups {
+    /// Get the supplementary groups of a user
+    /// calls keycloak::get_groups_by_user underneath
+    /// Returns Response::Success with the user's groups if the user is found
+    /// Returns Response::NotFound if the user is not found
+    fn get_entries_by_user(user: String) -> Response<Vec<Group>> {
+        crate::daemon::resolve(Request::GroupsByUser(user.clone()), || {
+            crate::lookup("get groups by user", |config, client, access_token| {
+                let groups = get_groups_by_user(
+                    &config.keycloak,
+                    &config.mapping,
+                    access_token,
+                    &user,
+                    client,
+                )?;
+                Ok(groups.map(|groups| groups.into_iter().map(Group::from).collect()))
+            })
+        })
+    }
+}
@@ -1,4 +1,5 @@
 use std::ops::{Add, Sub};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 
 #[cfg(feature = "mock")]
@@ -6,14 +7,130 @@ use mock_instant::SystemTime;
 #[cfg(not(feature = "mock"))]
 use std::time::SystemTime;
 
-use anyhow::{Ok, Result};
-use serde::Deserialize;
+use anyhow::{Context, Ok, Result};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
 
-use crate::config::KeycloakConfig;
+use super::client::{send, HttpClient};
+use super::endpoints::{discover_token_url, token_url};
+use super::error::{KeycloakError, OAuthError};
+use crate::config::{secret_path, AuthMethod, KeycloakConfig, SigningAlgorithm};
+use crate::disk_cache;
 
 // some time buffer to avoid token expiration issues
 // between the time we check validate the token and the time we use it
 const TIMEBUFFER: Duration = Duration::from_secs(3);
+// lifetime of a client assertion, it is only used for a single token request
+const ASSERTION_LIFETIME: Duration = Duration::from_secs(60);
+const ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+// makes the ids of client assertions signed within the same second unique
+static ASSERTION_COUNTER: AtomicUsize = AtomicUsize::new(0);
+// file in the state directory telling all processes that the credentials were rejected
+const REJECTED_FILE_NAME: &str = "credentials-rejected.json";
+
+/// Credentials the client authenticates itself with at the token endpoint
+enum ClientAuth {
+    Secret(String),
+    PrivateKeyJwt(Box<(EncodingKey, Header)>),
+    // the certificate is presented by the HTTP client during the TLS handshake
+    TlsClientCertificate,
+}
+
+/// Claims of a client assertion, as required by Keycloak for signed JWT client authentication
+#[derive(Serialize)]
+struct AssertionClaims<'a> {
+    iss: &'a str,
+    sub: &'a str,
+    aud: &'a str,
+    jti: String,
+    iat: u64,
+    exp: u64,
+}
+
+impl ClientAuth {
+    /// Set up the client authentication, reading the private key if one is used
+    fn new(config: &KeycloakConfig) -> Result<ClientAuth> {
+        match config.auth_method {
+            AuthMethod::ClientSecret => Ok(ClientAuth::Secret(config.client_secret.clone())),
+            AuthMethod::PrivateKeyJwt => {
+                let file = config
+                    .private_key_file
+                    .as_deref()
+                    .context("keycloak.private_key_file is missing")?;
+                let path = secret_path(file)?;
+                let pem = std::fs::read(&path)
+                    .with_context(|| format!("Failed to read private key {}", path.display()))?;
+                let algorithm = match config.signing_algorithm {
+                    SigningAlgorithm::Rs256 => Algorithm::RS256,
+                    SigningAlgorithm::Rs384 => Algorithm::RS384,
+                    SigningAlgorithm::Rs512 => Algorithm::RS512,
+                    SigningAlgorithm::Ps256 => Algorithm::PS256,
+                    SigningAlgorithm::Ps384 => Algorithm::PS384,
+                    SigningAlgorithm::Ps512 => Algorithm::PS512,
+                    SigningAlgorithm::Es256 => Algorithm::ES256,
+                    SigningAlgorithm::Es384 => Algorithm::ES384,
+                };
+                let key = match algorithm {
+                    Algorithm::ES256 | Algorithm::ES384 => EncodingKey::from_ec_pem(&pem),
+                    _ => EncodingKey::from_rsa_pem(&pem),
+                }
+                .with_context(|| {
+                    format!(
+                        "Failed to parse private key {} for {:?}",
+                        path.display(),
+                        algorithm
+                    )
+                })?;
+                let mut header = Header::new(algorithm);
+                header.kid = config.key_id.clone();
+                Ok(ClientAuth::PrivateKeyJwt(Box::new((key, header))))
+            }
+            AuthMethod::TlsClientAuth => Ok(ClientAuth::TlsClientCertificate),
+        }
+    }
+
+    /// Form parameters that authenticate the client in a request to the token endpoint
+    fn form_params(
+        &self,
+        config: &KeycloakConfig,
+        token_url: &str,
+    ) -> Result<Vec<(&'static str, String)>> {
+        match self {
+            ClientAuth::Secret(secret) => Ok(vec![
+                ("client_id", config.client_id.clone()),
+                ("client_secret", secret.clone()),
+            ]),
+            ClientAuth::PrivateKeyJwt(signer) => {
+                let (key, header) = signer.as_ref();
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default();
+                let claims = AssertionClaims {
+                    iss: &config.client_id,
+                    sub: &config.client_id,
+                    aud: token_url,
+                    jti: format!(
+                        "{}-{}-{}",
+                        now.as_nanos(),
+                        std::process::id(),
+                        ASSERTION_COUNTER.fetch_add(1, Ordering::Relaxed)
+                    ),
+                    iat: now.as_secs(),
+                    exp: now.add(ASSERTION_LIFETIME).as_secs(),
+                };
+                let assertion = jsonwebtoken::encode(header, &claims, key)
+                    .context("Failed to sign client assertion")?;
+                Ok(vec![
+                    ("client_id", config.client_id.clone()),
+                    ("client_assertion_type", ASSERTION_TYPE.to_string()),
+                    ("client_assertion", assertion),
+                ])
+            }
+            ClientAuth::TlsClientCertificate => Ok(vec![("client_id", config.client_id.clone())]),
+        }
+    }
+}
 
 /// test if the access token is still valid.
 fn access_token_is_valid(token: &KeycloakToken) -> bool {
@@ -36,7 +153,8 @@ fn format_token(json_response: &str, request_time: &SystemTime) -> Result<Keyclo
         refresh_token: Option<String>, // refresh token can be missing if it has 0 lifetime
         refresh_expires_in: u64,
     }
-    let token_response = serde_json::from_str::<KeycloakTokenResponse>(json_response)?;
+    let token_response = serde_json::from_str::<KeycloakTokenResponse>(json_response)
+        .context("Failed to parse the response of the token endpoint")?;
     // calculate the expiration time of the access token and the refresh token, and
     // subtract a time buffer to expiration issues near the expiration time
     let access_token_expiration = request_time
@@ -54,58 +172,61 @@ fn format_token(json_response: &str, request_time: &SystemTime) -> Result<Keyclo
 }
 
 /// fetch a new access token from Keycloak using the given config
-fn get_token(config: &KeycloakConfig) -> Result<KeycloakToken> {
-    let client = reqwest::blocking::Client::new();
+fn get_token(
+    config: &KeycloakConfig,
+    client: &HttpClient,
+    client_auth: &ClientAuth,
+    token_url: &str,
+) -> Result<KeycloakToken> {
+    let client = client.get()?;
     // build request parameters based on whether username and password are provided
     // if they are provided, use the password grant type
     // else, use the client credentials grant type (this assumes a qualified service account for this client)
-    let form_params = match (&config.username, &config.password) {
+    let mut form_params = match (&config.username, &config.password) {
         (Some(username), Some(password)) => vec![
-            ("grant_type", "password"),
-            ("client_id", &config.client_id),
-            ("client_secret", &config.client_secret),
-            ("username", &username),
-            ("password", &password),
-        ],
-        _ => vec![
-            ("grant_type", "client_credentials"),
-            ("client_id", &config.client_id),
-            ("client_secret", &config.client_secret),
+            ("grant_type", "password".to_string()),
+            ("username", username.clone()),
+            ("password", password.clone()),
         ],
+        _ => vec![("grant_type", "client_credentials".to_string())],
     };
+    form_params.extend(client_auth.form_params(config, token_url)?);
     // save request time to calculate token expiration
     let request_time = SystemTime::now();
     // send request to Keycloak token endpoint
-    let response = client
-        .post(&format!(
-            "{}/realms/{}/protocol/openid-connect/token",
-            config.url, config.realm
-        ))
-        .form(&form_params)
-        .send()?;
+    let response = send(config, &client, client.post(token_url).form(&form_params))?;
     // then parse the response and format it into a KeycloakToken
     format_token(&response.text()?, &request_time)
 }
 
 /// refresh the access token using the refresh token
-fn refresh_token(config: &KeycloakConfig, token: &KeycloakToken) -> Result<KeycloakToken> {
-    let client = reqwest::blocking::Client::new();
+fn refresh_token(
+    config: &KeycloakConfig,
+    client: &HttpClient,
+    client_auth: &ClientAuth,
+    token_url: &str,
+    token: &KeycloakToken,
+) -> Result<KeycloakToken> {
+    let client = client.get()?;
+    let mut form_params = vec![
+        ("grant_type", "refresh_token".to_string()),
+        ("refresh_token", token.refresh_token.clone()),
+    ];
+    form_params.extend(client_auth.form_params(config, token_url)?);
     let request_time = SystemTime::now();
-    let response = client
-        .post(&format!(
-            "{}/realms/{}/protocol/openid-connect/token",
-            config.url, config.realm
-        ))
-        .form(&[
-            ("grant_type", "refresh_token"),
-            ("client_id", &config.client_id),
-            ("client_secret", &config.client_secret),
-            ("refresh_token", &token.refresh_token),
-        ])
-        .send()?;
+    let response = send(config, &client, client.post(token_url).form(&form_params))?;
     format_token(&response.text()?, &request_time)
 }
 
+/// Rejected credentials as shared with the other processes of the host
+#[derive(Serialize, Deserialize)]
+struct StoredRejection {
+    // seconds since the epoch until which the credentials are not sent again
+    until: u64,
+    #[serde(flatten)]
+    error: OAuthError,
+}
+
 /// data structure for a Keycloak token
 /// contains the access token and its expiration date
 /// and the refresh token and its expiration date
@@ -118,7 +239,15 @@ struct KeycloakToken {
 
 pub struct KeycloakAuth<'a> {
     keycloak_config: &'a KeycloakConfig,
+    client: &'a HttpClient<'a>,
+    client_auth: ClientAuth,
+    // resolved on first use, as discovery requires a request to Keycloak
+    token_url: Option<String>,
     token: Option<KeycloakToken>,
+    // the error of rejected credentials, and the time until which they are not sent again
+    rejected: Option<(SystemTime, OAuthError)>,
+    // directory to share rejected credentials with the other processes of the host
+    state_directory: Option<String>,
 }
 
 /// Base trait for Keycloak authentication
@@ -127,6 +256,7 @@ pub struct KeycloakAuth<'a> {
 pub trait TokenProvider {
     fn get_access_token(&mut self) -> Result<&String>;
     fn has_valid_token(&self) -> bool;
+    fn invalidate_access_token(&mut self);
 }
 
 impl TokenProvider for KeycloakAuth<'_> {
@@ -140,13 +270,34 @@ impl TokenProvider for KeycloakAuth<'_> {
             // token is valid, no action required
         } else if self.token.is_some() && refresh_token_is_valid(self.token.as_ref().unwrap()) {
             // refresh token is valid, get a new access token
-            self.token = Some(refresh_token(
+            let token_url = self.token_url()?;
+            let refreshed = refresh_token(
                 self.keycloak_config,
+                self.client,
+                &self.client_auth,
+                &token_url,
                 self.token.as_ref().unwrap(),
-            )?);
+            );
+            self.token = Some(match refreshed {
+                // e.g. the session of the refresh token ended before it expired
+                Err(err)
+                    if matches!(
+                        KeycloakError::find(&err),
+                        Some(KeycloakError::OAuth(_) | KeycloakError::Unauthorized)
+                    ) =>
+                {
+                    log::info!(
+                        "Failed to refresh the access token, getting a new one: {:#}",
+                        err
+                    );
+                    self.new_token(&token_url)?
+                }
+                result => result?,
+            });
         } else {
             // no token or no valid token, get a new token using the direct access grant flow
-            self.token = Some(get_token(self.keycloak_config)?);
+            let token_url = self.token_url()?;
+            self.token = Some(self.new_token(&token_url)?);
         }
         // return the access token
         match &self.token {
@@ -161,18 +312,129 @@ impl TokenProvider for KeycloakAuth<'_> {
             None => false,
         }
     }
+
+    /// Mark the access token as expired, e.g. after Keycloak rejected it.
+    /// The next call to get_access_token will refresh it.
+    fn invalidate_access_token(&mut self) {
+        if let Some(ref mut token) = self.token {
+            token.access_token_expiration = SystemTime::now();
+        }
+    }
 }
 
-impl KeycloakAuth<'_> {
+impl<'a> KeycloakAuth<'a> {
     /// create a new KeycloakAuth instance
-    /// try to get a token from the Keycloak server using the direct access grant flow
-    pub fn new(keycloak_config: &KeycloakConfig) -> Result<KeycloakAuth> {
+    /// reads the private key if the client authenticates with signed JWTs
+    pub fn new(
+        keycloak_config: &'a KeycloakConfig,
+        client: &'a HttpClient<'a>,
+    ) -> Result<KeycloakAuth<'a>> {
         Ok(KeycloakAuth {
             keycloak_config,
+            client,
+            client_auth: ClientAuth::new(keycloak_config)?,
+            token_url: None,
             token: None,
+            rejected: None,
+            state_directory: None,
         })
     }
 
+    /// share rejected credentials with the other processes of the host through a file
+    /// in the given directory, so that a short-lived process does not send them again
+    pub fn with_state_directory(mut self, directory: Option<&str>) -> Self {
+        self.state_directory = directory.map(str::to_string);
+        self
+    }
+
+    /// get the rejection of the credentials by this or another process, if it has not expired
+    fn rejection(&self) -> Option<(SystemTime, OAuthError)> {
+        let now = SystemTime::now();
+        if let Some(rejected) = self.rejected.as_ref().filter(|(until, _)| now < *until) {
+            return Some(rejected.clone());
+        }
+        let directory = self.state_directory.as_deref()?;
+        let stored = disk_cache::read_state::<StoredRejection>(directory, REJECTED_FILE_NAME)
+            .unwrap_or_else(|err| {
+                log::debug!("Failed to read rejected credentials: {:?}", err);
+                None
+            })?;
+        let until = SystemTime::UNIX_EPOCH.add(Duration::from_secs(stored.until));
+        (now < until).then_some((until, stored.error))
+    }
+
+    /// remember the outcome of a token request, in this process and for the others
+    fn record_rejection(&mut self, rejected: Option<(SystemTime, OAuthError)>) {
+        if let Some(directory) = self.state_directory.as_deref() {
+            let result = match &rejected {
+                Some((until, error)) => disk_cache::write_state(
+                    directory,
+                    REJECTED_FILE_NAME,
+                    &StoredRejection {
+                        until: until
+                            .duration_since(SystemTime::UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_secs(),
+                        error: error.clone(),
+                    },
+                ),
+                None => disk_cache::remove_state(directory, REJECTED_FILE_NAME),
+            };
+            if let Err(err) = result {
+                log::debug!("Failed to update rejected credentials: {:?}", err);
+            }
+        }
+        self.rejected = rejected;
+    }
+
+    /// get a new token, unless Keycloak rejected the credentials recently
+    /// Otherwise a host with wrong credentials sends them with every lookup, which
+    /// can get the service account locked by the brute force detection of Keycloak.
+    fn new_token(&mut self, token_url: &str) -> Result<KeycloakToken> {
+        if let Some((_, err)) = self.rejection() {
+            return Err(anyhow::Error::from(KeycloakError::OAuth(err)).context(
+                "Keycloak rejected the credentials recently, not sending them again yet",
+            ));
+        }
+        let result = get_token(
+            self.keycloak_config,
+            self.client,
+            &self.client_auth,
+            token_url,
+        );
+        match &result {
+            Err(err) => {
+                if let Some(KeycloakError::OAuth(oauth)) = KeycloakError::find(err) {
+                    if oauth.is_bad_credentials() {
+                        let backoff =
+                            Duration::from_secs(self.keycloak_config.bad_credentials_backoff);
+                        self.record_rejection(Some((
+                            SystemTime::now().add(backoff),
+                            oauth.clone(),
+                        )));
+                    }
+                }
+            }
+            _ => self.record_rejection(None),
+        }
+        result
+    }
+
+    /// get the URL of the token endpoint
+    /// it is discovered on first use if discovery is enabled, and built from the url otherwise
+    pub fn token_url(&mut self) -> Result<String> {
+        if let Some(token_url) = &self.token_url {
+            return Ok(token_url.clone());
+        }
+        let token_url = if self.keycloak_config.discovery {
+            discover_token_url(self.keycloak_config, &self.client.get()?)?
+        } else {
+            token_url(self.keycloak_config)
+        };
+        self.token_url = Some(token_url.clone());
+        Ok(token_url)
+    }
+
     /// get the expiration time of the access token
     /// mainly for testing purposes
     pub fn access_token_expires_in(&self) -> Option<Duration> {
@@ -197,3 +459,93 @@ impl KeycloakAuth<'_> {
         }
     }
 }
+
+// -----------------------------------------------------------------------------------------------
+// --- Unit tests -------------------------------------------------------------------------------
+// -----------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{DecodingKey, Validation};
+
+    use crate::config::test_keycloak_config;
+
+    /// Test that a client secret is sent as is
+    #[test]
+    fn test_client_secret_params() {
+        let config = test_keycloak_config("");
+        let params = ClientAuth::new(&config)
+            .unwrap()
+            .form_params(&config, &token_url(&config))
+            .unwrap();
+        assert!(params.contains(&("client_secret", "mysecret".to_string())));
+    }
+
+    /// Test that a client assertion is signed with the private key and has the claims
+    /// Keycloak requires
+    #[test]
+    fn test_private_key_jwt_params() {
+        let config = test_keycloak_config(&format!(
+            r#"
+            auth_method = "private_key_jwt"
+            private_key_file = "{}/tests/files/client-key.pem"
+            signing_algorithm = "ES256"
+            key_id = "nss-key"
+            "#,
+            env!("CARGO_MANIFEST_DIR")
+        ));
+        let params = ClientAuth::new(&config)
+            .unwrap()
+            .form_params(&config, &token_url(&config))
+            .unwrap();
+        assert!(params.contains(&("client_assertion_type", ASSERTION_TYPE.to_string())));
+        assert!(!params.iter().any(|(key, _)| *key == "client_secret"));
+        let (_, assertion) = params
+            .iter()
+            .find(|(key, _)| *key == "client_assertion")
+            .unwrap();
+
+        #[derive(Deserialize)]
+        struct Claims {
+            iss: String,
+            sub: String,
+            jti: String,
+            iat: u64,
+            exp: u64,
+        }
+        let public_key = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/files/client-key.pub.pem"
+        ))
+        .unwrap();
+        let mut validation = Validation::new(Algorithm::ES256);
+        validation.set_audience(&[token_url(&config)]);
+        // the clock may be mocked
+        validation.validate_exp = false;
+        let decoded = jsonwebtoken::decode::<Claims>(
+            assertion,
+            &DecodingKey::from_ec_pem(&public_key).unwrap(),
+            &validation,
+        )
+        .unwrap();
+        assert_eq!(decoded.header.kid.as_deref(), Some("nss-key"));
+        assert_eq!(decoded.claims.iss, "myclient");
+        assert_eq!(decoded.claims.sub, "myclient");
+        assert!(!decoded.claims.jti.is_empty());
+        assert_eq!(decoded.claims.exp - decoded.claims.iat, 60);
+    }
+
+    /// Test that a key that does not match the algorithm is rejected
+    #[test]
+    fn test_private_key_jwt_wrong_algorithm() {
+        let config = test_keycloak_config(&format!(
+            r#"
+            auth_method = "private_key_jwt"
+            private_key_file = "{}/tests/files/client-key.pem"
+            "#,
+            env!("CARGO_MANIFEST_DIR")
+        ));
+        let err = ClientAuth::new(&config).err().unwrap();
+        assert!(err.to_string().contains("Failed to parse private key"));
+    }
+}
@@ -0,0 +1,485 @@
+use std::cell::Cell;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use reqwest::blocking::{Client, Request, RequestBuilder, Response};
+use reqwest::{Method, NoProxy, Proxy, StatusCode, Url};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::error::{KeycloakError, OAuthError};
+use super::tls;
+use crate::config::KeycloakConfig;
+
+/// Time limits of the lookup currently running on a thread, see `with_deadline`
+#[derive(Clone, Copy)]
+struct Deadline {
+    expires: Instant,
+    request_timeout: Duration,
+}
+
+thread_local! {
+    static DEADLINE: Cell<Option<Deadline>> = const { Cell::new(None) };
+}
+
+// endpoints that failed recently, with the time until which they are tried last
+static UNHEALTHY: Mutex<Vec<(String, Instant)>> = Mutex::new(Vec::new());
+
+/// Stops sending requests to Keycloak for a while after repeated failures, so that
+/// lookups fail fast instead of each waiting for Keycloak to time out
+struct CircuitBreaker {
+    // consecutive failed requests, each counted once including its retries and failover
+    failures: u32,
+    // no requests are sent until then
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    fn is_open(&self, now: Instant) -> bool {
+        self.open_until.is_some_and(|until| until > now)
+    }
+
+    /// Count a request. The breaker opens when `threshold` requests failed in a row.
+    /// After the cooldown, a single failed request opens it again.
+    fn record(&mut self, failed: bool, threshold: u32, cooldown: Duration, now: Instant) {
+        if !failed {
+            self.failures = 0;
+            self.open_until = None;
+            return;
+        }
+        self.failures = self.failures.saturating_add(1);
+        if threshold > 0 && self.failures >= threshold && !self.is_open(now) {
+            log::warn!(
+                "{} requests to Keycloak failed in a row, not sending requests for {} s",
+                self.failures,
+                cooldown.as_secs()
+            );
+            self.open_until = Some(now + cooldown);
+        }
+    }
+}
+
+static BREAKER: Mutex<CircuitBreaker> = Mutex::new(CircuitBreaker {
+    failures: 0,
+    open_until: None,
+});
+
+/// Check if requests to Keycloak are suspended by the circuit breaker
+fn circuit_open() -> bool {
+    BREAKER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .is_open(Instant::now())
+}
+
+/// Build a new HTTP client for requests to Keycloak from the given config
+fn build_client(config: &KeycloakConfig) -> Result<Client> {
+    let mut builder = Client::builder()
+        .pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout))
+        .tcp_keepalive(Duration::from_secs(config.pool_idle_timeout))
+        .connect_timeout(Duration::from_secs(config.connect_timeout))
+        .timeout(Duration::from_secs(config.request_timeout));
+    // a configured proxy replaces the proxies of the environment
+    if let Some(proxy) = &config.proxy {
+        let no_proxy = NoProxy::from_string(&config.no_proxy.join(","));
+        builder = builder.proxy(Proxy::all(proxy)?.no_proxy(no_proxy));
+    } else if config.ignore_env_proxy {
+        builder = builder.no_proxy();
+    }
+    Ok(builder
+        .use_preconfigured_tls(tls::client_config(config)?)
+        .build()?)
+}
+
+/// Run `f` with a deadline of `lookup_timeout` seconds for all requests sent with `send`
+/// on the current thread. A single lookup may consist of many requests, so the
+/// request timeout alone does not limit the time spent in a lookup.
+pub fn with_deadline<T>(config: &KeycloakConfig, f: impl FnOnce() -> T) -> T {
+    // a lookup timeout too large for a deadline does not limit the lookup
+    let Some(expires) = Instant::now().checked_add(Duration::from_secs(config.lookup_timeout))
+    else {
+        return f();
+    };
+    let deadline = Deadline {
+        expires,
+        request_timeout: Duration::from_secs(config.request_timeout),
+    };
+    let previous = DEADLINE.with(|cell| cell.replace(Some(deadline)));
+    let result = f();
+    DEADLINE.with(|cell| cell.set(previous));
+    result
+}
+
+/// Time left for a request within `with_deadline`, or None outside of it
+fn remaining_time() -> std::result::Result<Option<Duration>, KeycloakError> {
+    match DEADLINE.with(|cell| cell.get()) {
+        Some(deadline) => {
+            let remaining = deadline.expires.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(KeycloakError::Timeout);
+            }
+            Ok(Some(remaining.min(deadline.request_timeout)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Check if the lookup deadline, if any, leaves time to wait for `delay`
+fn deadline_allows(delay: Duration) -> bool {
+    match DEADLINE.with(|cell| cell.get()) {
+        Some(deadline) => Instant::now() + delay < deadline.expires,
+        None => true,
+    }
+}
+
+/// Randomize a backoff to between half and all of it, so that the processes
+/// waiting for Keycloak do not all retry at the same time
+fn jittered(backoff: Duration) -> Duration {
+    let mut random = [0u8; 4];
+    // without randomness, the shortest backoff is used
+    let _ = SystemRandom::new().fill(&mut random);
+    let fraction = f64::from(u32::from_ne_bytes(random)) / f64::from(u32::MAX);
+    backoff.mul_f64(0.5 + fraction / 2.0)
+}
+
+/// Order in which to try the endpoints: those that have not failed recently first,
+/// then the others as a last resort, each in the configured order
+fn endpoint_order<'a>(
+    endpoints: &[&'a str],
+    unhealthy: &[(String, Instant)],
+    now: Instant,
+) -> Vec<&'a str> {
+    let (healthy, cooling_down): (Vec<&str>, Vec<&str>) = endpoints.iter().partition(|endpoint| {
+        !unhealthy
+            .iter()
+            .any(|(url, until)| url == *endpoint && *until > now)
+    });
+    healthy.into_iter().chain(cooling_down).collect()
+}
+
+/// Mark an endpoint as failed, so that it is skipped for the cooldown period
+fn mark_unhealthy(endpoint: &str, cooldown: Duration) {
+    let now = Instant::now();
+    let mut unhealthy = UNHEALTHY.lock().unwrap_or_else(PoisonError::into_inner);
+    unhealthy.retain(|(url, until)| url != endpoint && *until > now);
+    unhealthy.push((endpoint.to_string(), now + cooldown));
+}
+
+/// Mark an endpoint as working again
+fn mark_healthy(endpoint: &str) {
+    let mut unhealthy = UNHEALTHY.lock().unwrap_or_else(PoisonError::into_inner);
+    if !unhealthy.is_empty() {
+        unhealthy.retain(|(url, _)| url != endpoint);
+    }
+}
+
+/// Check if an error means that the endpoint is down, rather than the request being wrong
+fn is_endpoint_failure(err: &KeycloakError) -> bool {
+    match err {
+        KeycloakError::Network(_) | KeycloakError::Timeout => true,
+        // returned by load balancers and proxies without a working backend
+        KeycloakError::ServerError(status) => matches!(status.as_u16(), 502..=504),
+        _ => false,
+    }
+}
+
+/// Turn responses with an error status into errors. The token endpoint explains
+/// its 400 and 401 responses with an OAuth error in the body.
+fn check_status(response: Response) -> std::result::Result<Response, KeycloakError> {
+    let status = response.status();
+    let Some(err) = KeycloakError::from_status(status) else {
+        return Ok(response);
+    };
+    if matches!(status, StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED) {
+        if let Some(oauth) = response
+            .text()
+            .ok()
+            .and_then(|body| OAuthError::from_body(&body))
+        {
+            return Err(KeycloakError::OAuth(oauth));
+        }
+    }
+    Err(err)
+}
+
+/// Send a single request. Responses with an error status are returned as errors,
+/// before their body is parsed.
+fn execute(client: &Client, mut request: Request) -> std::result::Result<Response, KeycloakError> {
+    if let Some(timeout) = remaining_time()? {
+        // overrides the request timeout of the client
+        *request.timeout_mut() = Some(timeout);
+    }
+    client
+        .execute(request)
+        .map_err(KeycloakError::from)
+        .and_then(check_status)
+}
+
+/// Check if the outcome of a request counts as a failure for the circuit breaker.
+/// Timeouts after the lookup deadline has passed are caused by the lookup taking
+/// too long, not by Keycloak, and are not counted.
+fn is_breaker_failure(result: &std::result::Result<Response, KeycloakError>) -> bool {
+    match result {
+        Err(KeycloakError::Timeout) => remaining_time().is_ok(),
+        Err(err) => is_endpoint_failure(err),
+        Ok(_) => false,
+    }
+}
+
+/// Send a request to Keycloak. Within `with_deadline`, the request is not sent
+/// if the deadline has passed and its timeout is limited to the remaining time.
+/// GET requests that failed because Keycloak is down are retried with a jittered,
+/// exponentially growing backoff, as long as the deadline allows. While the circuit
+/// breaker is open, no request is sent at all. The circuit breaker counts the
+/// outcome once, after all retries and failover.
+pub(crate) fn send(
+    config: &KeycloakConfig,
+    client: &Client,
+    request: RequestBuilder,
+) -> std::result::Result<Response, KeycloakError> {
+    let request = request.build()?;
+    if circuit_open() {
+        return Err(KeycloakError::CircuitOpen);
+    }
+    let result = send_with_retries(config, client, request);
+    BREAKER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .record(
+            is_breaker_failure(&result),
+            config.circuit_breaker_threshold,
+            Duration::from_secs(config.circuit_breaker_cooldown),
+            Instant::now(),
+        );
+    result
+}
+
+/// Send a request, retrying GET requests while Keycloak is down and the deadline allows
+fn send_with_retries(
+    config: &KeycloakConfig,
+    client: &Client,
+    request: Request,
+) -> std::result::Result<Response, KeycloakError> {
+    // only GET requests are idempotent, token requests are not retried
+    let retries = if request.method() == Method::GET {
+        config.retries
+    } else {
+        0
+    };
+    let mut backoff = Duration::from_millis(config.retry_backoff);
+    for _ in 0..retries {
+        let Some(attempt) = request.try_clone() else {
+            break;
+        };
+        match send_with_failover(config, client, attempt) {
+            Err(err) if is_endpoint_failure(&err) => {
+                let delay = jittered(backoff);
+                if circuit_open() || !deadline_allows(delay) {
+                    return Err(err);
+                }
+                log::warn!(
+                    "Request to Keycloak failed, retrying in {} ms: {}",
+                    delay.as_millis(),
+                    err
+                );
+                std::thread::sleep(delay);
+                backoff = backoff.saturating_mul(2);
+            }
+            result => return result,
+        }
+    }
+    send_with_failover(config, client, request)
+}
+
+/// Send a request, failing over from `url` to the fallback URLs if it is down.
+/// Endpoints that failed are tried last until their cooldown has passed.
+fn send_with_failover(
+    config: &KeycloakConfig,
+    client: &Client,
+    request: Request,
+) -> std::result::Result<Response, KeycloakError> {
+    let base = config.url.trim_end_matches('/');
+    let path = match request.url().as_str().strip_prefix(base) {
+        Some(path) if !config.fallback_urls.is_empty() => path.to_string(),
+        _ => return execute(client, request),
+    };
+    let endpoints: Vec<&str> = std::iter::once(base)
+        .chain(
+            config
+                .fallback_urls
+                .iter()
+                .map(|url| url.trim_end_matches('/')),
+        )
+        .collect();
+    let order = {
+        let unhealthy = UNHEALTHY.lock().unwrap_or_else(PoisonError::into_inner);
+        endpoint_order(&endpoints, &unhealthy, Instant::now())
+    };
+    let mut last_err = KeycloakError::Timeout;
+    for endpoint in order {
+        // the requests to Keycloak have no streaming bodies and can always be cloned
+        let (Some(mut attempt), Ok(url)) = (
+            request.try_clone(),
+            Url::parse(&format!("{}{}", endpoint, path)),
+        ) else {
+            continue;
+        };
+        *attempt.url_mut() = url;
+        // do not blame the endpoint for the deadline of the lookup
+        remaining_time()?;
+        match execute(client, attempt) {
+            Err(err) if is_endpoint_failure(&err) => {
+                log::warn!("Keycloak at {} failed: {}", endpoint, err);
+                mark_unhealthy(endpoint, Duration::from_secs(config.failover_cooldown));
+                last_err = err;
+            }
+            result => {
+                mark_healthy(endpoint);
+                return result;
+            }
+        }
+    }
+    Err(last_err)
+}
+
+/// Check if an error was caused by Keycloak not answering in time
+pub fn is_timeout(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<KeycloakError>(),
+            Some(KeycloakError::Timeout)
+        ) || matches!(cause.downcast_ref::<reqwest::Error>(), Some(err) if err.is_timeout())
+    })
+}
+
+/// Process-wide HTTP client for all requests to Keycloak.
+/// The client keeps idle connections open, so that consecutive lookups reuse
+/// them instead of paying for a new connection and TLS handshake every time.
+pub struct HttpClient<'a> {
+    config: &'a KeycloakConfig,
+    // the client together with the id of the process that created it
+    client: Mutex<Option<(u32, Client)>>,
+}
+
+impl HttpClient<'_> {
+    /// create a new HttpClient instance
+    /// the underlying client is built on first use
+    pub fn new(config: &KeycloakConfig) -> HttpClient<'_> {
+        HttpClient {
+            config,
+            client: Mutex::new(None),
+        }
+    }
+
+    /// get the shared client, building it if necessary
+    /// The blocking client relies on a background thread, which does not
+    /// exist in processes forked after the client was built (e.g. by sshd),
+    /// so a new client is built when used from a different process.
+    pub fn get(&self) -> Result<Client> {
+        let pid = std::process::id();
+        let mut client = self.client.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((client_pid, client)) = client.as_ref() {
+            if *client_pid == pid {
+                return Ok(client.clone());
+            }
+        }
+        // dropping a client inherited from the parent process would wait for
+        // its background thread, which does not exist in this process
+        if let Some((_, inherited)) = client.take() {
+            std::mem::forget(inherited);
+        }
+        let new_client = build_client(self.config)?;
+        *client = Some((pid, new_client.clone()));
+        Ok(new_client)
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// --- Unit tests -------------------------------------------------------------------------------
+// -----------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_keycloak_config;
+
+    /// Test that endpoints in their cooldown are tried last, until the cooldown has passed
+    #[test]
+    fn test_endpoint_order() {
+        let endpoints = ["https://sso1", "https://sso2", "https://sso3"];
+        let now = Instant::now();
+        assert_eq!(endpoint_order(&endpoints, &[], now), endpoints);
+
+        let unhealthy = vec![
+            ("https://sso1".to_string(), now + Duration::from_secs(30)),
+            ("https://sso2".to_string(), now),
+        ];
+        assert_eq!(
+            endpoint_order(&endpoints, &unhealthy, now),
+            ["https://sso2", "https://sso3", "https://sso1"]
+        );
+        assert_eq!(
+            endpoint_order(&endpoints, &unhealthy, now + Duration::from_secs(31)),
+            endpoints
+        );
+    }
+
+    /// Test that the breaker opens after the threshold, reopens after a single failure
+    /// following the cooldown, and closes on success
+    #[test]
+    fn test_circuit_breaker() {
+        let cooldown = Duration::from_secs(30);
+        let now = Instant::now();
+        let mut breaker = CircuitBreaker {
+            failures: 0,
+            open_until: None,
+        };
+        breaker.record(true, 3, cooldown, now);
+        breaker.record(true, 3, cooldown, now);
+        assert!(!breaker.is_open(now));
+        breaker.record(true, 3, cooldown, now);
+        assert!(breaker.is_open(now));
+
+        let later = now + Duration::from_secs(31);
+        assert!(!breaker.is_open(later));
+        breaker.record(true, 3, cooldown, later);
+        assert!(breaker.is_open(later));
+
+        breaker.record(false, 3, cooldown, later);
+        assert!(!breaker.is_open(later));
+        assert_eq!(breaker.failures, 0);
+
+        // a threshold of 0 disables the breaker
+        for _ in 0..10 {
+            breaker.record(true, 0, cooldown, now);
+        }
+        assert!(!breaker.is_open(now));
+    }
+
+    /// Test that the jittered backoff is between half and all of the backoff
+    #[test]
+    fn test_jittered() {
+        let backoff = Duration::from_millis(200);
+        for _ in 0..100 {
+            let delay = jittered(backoff);
+            assert!(delay >= backoff / 2 && delay <= backoff);
+        }
+    }
+
+    /// Test that only failures of Keycloak count for the circuit breaker, and not
+    /// timeouts caused by the deadline of the lookup
+    #[test]
+    fn test_is_breaker_failure() {
+        assert!(is_breaker_failure(&Err(KeycloakError::Timeout)));
+        assert!(is_breaker_failure(&Err(KeycloakError::ServerError(
+            StatusCode::SERVICE_UNAVAILABLE
+        ))));
+        assert!(!is_breaker_failure(&Err(KeycloakError::NotFound)));
+        let config = test_keycloak_config("lookup_timeout = 0");
+        with_deadline(&config, || {
+            assert!(!is_breaker_failure(&Err(KeycloakError::Timeout)));
+            assert!(is_breaker_failure(&Err(KeycloakError::ServerError(
+                StatusCode::BAD_GATEWAY
+            ))));
+        });
+    }
+}
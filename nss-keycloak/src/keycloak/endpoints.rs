@@ -0,0 +1,153 @@
+use anyhow::{anyhow, Context, Result};
+use reqwest::blocking::Client;
+use serde::Deserialize;
+
+use super::client::send;
+use crate::config::{check_url, KeycloakConfig};
+
+/// Base URL of the admin REST API of the realm
+pub(crate) fn admin_realm_url(config: &KeycloakConfig) -> String {
+    format!(
+        "{}/admin/realms/{}",
+        config
+            .admin_url
+            .as_deref()
+            .unwrap_or(&config.url)
+            .trim_end_matches('/'),
+        config.realm
+    )
+}
+
+/// Issuer of the realm, the base URL of its OpenID Connect endpoints
+pub(crate) fn issuer(config: &KeycloakConfig) -> String {
+    match &config.issuer {
+        Some(issuer) => issuer.trim_end_matches('/').to_string(),
+        None => format!(
+            "{}/realms/{}",
+            config.url.trim_end_matches('/'),
+            config.realm
+        ),
+    }
+}
+
+/// URL of the token endpoint of the realm, as served by Keycloak at `url`
+pub(crate) fn token_url(config: &KeycloakConfig) -> String {
+    format!(
+        "{}/realms/{}/protocol/openid-connect/token",
+        config.url.trim_end_matches('/'),
+        config.realm
+    )
+}
+
+/// Get the token endpoint from an OpenID Connect discovery document.
+/// The document must be the one of the expected issuer, and the endpoint must pass
+/// the same checks as the configured URLs.
+fn token_endpoint(document: &str, issuer: &str, allow_http: bool) -> Result<String> {
+    /// the fields of the discovery document used by the plugin
+    #[derive(Deserialize)]
+    struct Discovery {
+        issuer: String,
+        token_endpoint: String,
+    }
+    let discovery = serde_json::from_str::<Discovery>(document)?;
+    if discovery.issuer.trim_end_matches('/') != issuer {
+        return Err(anyhow!(
+            "the discovery document is for issuer {}, expected {}",
+            discovery.issuer,
+            issuer
+        ));
+    }
+    let mut problems = vec![];
+    check_url(
+        "token_endpoint",
+        &discovery.token_endpoint,
+        allow_http,
+        &mut problems,
+    );
+    match problems.pop() {
+        Some(problem) => Err(anyhow!(problem)),
+        None => Ok(discovery.token_endpoint),
+    }
+}
+
+/// Get the token endpoint of the realm from its OpenID Connect discovery document
+/// at {issuer}/.well-known/openid-configuration
+pub(crate) fn discover_token_url(config: &KeycloakConfig, client: &Client) -> Result<String> {
+    let issuer = issuer(config);
+    let url = format!("{}/.well-known/openid-configuration", issuer);
+    let response =
+        send(config, client, client.get(&url)).with_context(|| format!("Failed to get {}", url))?;
+    token_endpoint(&response.text()?, &issuer, config.allow_http)
+        .with_context(|| format!("Failed to read the discovery document {}", url))
+}
+
+// -----------------------------------------------------------------------------------------------
+// --- Unit tests -------------------------------------------------------------------------------
+// -----------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_keycloak_config;
+
+    /// Test that the endpoints are derived from `url` unless configured
+    #[test]
+    fn test_endpoints() {
+        let config = KeycloakConfig {
+            url: "https://sso.example.com/auth/".to_string(),
+            ..test_keycloak_config("")
+        };
+        assert_eq!(
+            admin_realm_url(&config),
+            "https://sso.example.com/auth/admin/realms/myrealm"
+        );
+        assert_eq!(
+            issuer(&config),
+            "https://sso.example.com/auth/realms/myrealm"
+        );
+        assert_eq!(
+            token_url(&config),
+            "https://sso.example.com/auth/realms/myrealm/protocol/openid-connect/token"
+        );
+
+        let config = KeycloakConfig {
+            admin_url: Some("https://admin.example.com/".to_string()),
+            issuer: Some("https://login.example.com/realms/myrealm".to_string()),
+            ..config
+        };
+        assert_eq!(
+            admin_realm_url(&config),
+            "https://admin.example.com/admin/realms/myrealm"
+        );
+        assert_eq!(issuer(&config), "https://login.example.com/realms/myrealm");
+    }
+
+    /// Test that the token endpoint is only taken from the document of the expected issuer
+    #[test]
+    fn test_token_endpoint() {
+        let document = r#"{
+            "issuer": "https://login.example.com/realms/myrealm",
+            "token_endpoint": "https://login.example.com/realms/myrealm/protocol/openid-connect/token",
+            "jwks_uri": "https://login.example.com/realms/myrealm/protocol/openid-connect/certs"
+        }"#;
+        assert_eq!(
+            token_endpoint(document, "https://login.example.com/realms/myrealm", false).unwrap(),
+            "https://login.example.com/realms/myrealm/protocol/openid-connect/token"
+        );
+        let err =
+            token_endpoint(document, "https://sso.example.com/realms/myrealm", false).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("expected https://sso.example.com/realms/myrealm"));
+        assert!(token_endpoint("{}", "https://login.example.com/realms/myrealm", false).is_err());
+
+        // the token endpoint must use HTTPS like the configured URLs
+        let document = r#"{
+            "issuer": "https://login.example.com/realms/myrealm",
+            "token_endpoint": "http://login.example.com/realms/myrealm/protocol/openid-connect/token"
+        }"#;
+        let err = token_endpoint(document, "https://login.example.com/realms/myrealm", false)
+            .unwrap_err();
+        assert!(err.to_string().contains("uses plain HTTP"));
+        assert!(token_endpoint(document, "https://login.example.com/realms/myrealm", true).is_ok());
+    }
+}
@@ -0,0 +1,266 @@
+use std::fmt;
+
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+/// Errors of requests to Keycloak, classified by their cause
+#[derive(Debug)]
+pub enum KeycloakError {
+    /// Keycloak rejected the credentials or the access token (401)
+    Unauthorized,
+    /// the client is not allowed to access the resource (403)
+    Forbidden,
+    /// the requested resource does not exist (404)
+    NotFound,
+    /// Keycloak failed to handle the request (5xx)
+    ServerError(StatusCode),
+    /// any other unexpected status code, e.g. 400 for an unsupported query
+    UnexpectedStatus(StatusCode),
+    /// Keycloak did not answer in time, or the lookup deadline has passed
+    Timeout,
+    /// Keycloak could not be reached
+    Network(reqwest::Error),
+    /// no request was sent, as Keycloak failed repeatedly (circuit breaker open)
+    CircuitOpen,
+    /// the token endpoint rejected the request with an OAuth error (400 or 401)
+    OAuth(OAuthError),
+}
+
+/// Error codes of the token endpoint, see RFC 6749, section 5.2
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "&'static str")]
+pub enum OAuthErrorCode {
+    /// the request is malformed, e.g. a parameter is missing
+    InvalidRequest,
+    /// the client authentication failed, e.g. a wrong client secret
+    InvalidClient,
+    /// the user credentials or the refresh token are invalid, expired or revoked
+    InvalidGrant,
+    /// the client is not allowed to use the grant type
+    UnauthorizedClient,
+    /// the grant type is not supported by Keycloak
+    UnsupportedGrantType,
+    /// the requested scope is invalid
+    InvalidScope,
+}
+
+impl OAuthErrorCode {
+    fn parse(code: &str) -> Option<OAuthErrorCode> {
+        match code {
+            "invalid_request" => Some(OAuthErrorCode::InvalidRequest),
+            "invalid_client" => Some(OAuthErrorCode::InvalidClient),
+            "invalid_grant" => Some(OAuthErrorCode::InvalidGrant),
+            "unauthorized_client" => Some(OAuthErrorCode::UnauthorizedClient),
+            "unsupported_grant_type" => Some(OAuthErrorCode::UnsupportedGrantType),
+            "invalid_scope" => Some(OAuthErrorCode::InvalidScope),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            OAuthErrorCode::InvalidRequest => "invalid_request",
+            OAuthErrorCode::InvalidClient => "invalid_client",
+            OAuthErrorCode::InvalidGrant => "invalid_grant",
+            OAuthErrorCode::UnauthorizedClient => "unauthorized_client",
+            OAuthErrorCode::UnsupportedGrantType => "unsupported_grant_type",
+            OAuthErrorCode::InvalidScope => "invalid_scope",
+        }
+    }
+}
+
+impl TryFrom<String> for OAuthErrorCode {
+    type Error = String;
+
+    fn try_from(code: String) -> Result<Self, Self::Error> {
+        OAuthErrorCode::parse(&code).ok_or_else(|| format!("unknown OAuth error code {}", code))
+    }
+}
+
+impl From<OAuthErrorCode> for &'static str {
+    fn from(code: OAuthErrorCode) -> Self {
+        code.as_str()
+    }
+}
+
+/// Error response of the token endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OAuthError {
+    pub code: OAuthErrorCode,
+    pub description: Option<String>,
+}
+
+impl OAuthError {
+    /// Parse the body of an error response.
+    /// Returns None if it is not an OAuth error, e.g. an error of the admin API.
+    pub fn from_body(body: &str) -> Option<OAuthError> {
+        #[derive(Deserialize)]
+        struct OAuthErrorResponse {
+            error: String,
+            error_description: Option<String>,
+        }
+        let response = serde_json::from_str::<OAuthErrorResponse>(body).ok()?;
+        Some(OAuthError {
+            code: OAuthErrorCode::parse(&response.error)?,
+            description: response.error_description,
+        })
+    }
+
+    /// Check if the error means that the configured credentials are wrong,
+    /// which retrying will not fix
+    pub fn is_bad_credentials(&self) -> bool {
+        matches!(
+            self.code,
+            OAuthErrorCode::InvalidClient
+                | OAuthErrorCode::InvalidGrant
+                | OAuthErrorCode::UnauthorizedClient
+        )
+    }
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.description {
+            Some(description) => write!(f, "{} ({})", self.code.as_str(), description),
+            None => write!(f, "{}", self.code.as_str()),
+        }
+    }
+}
+
+impl KeycloakError {
+    /// Classify the status code of a response.
+    /// Returns None for successful responses.
+    pub fn from_status(status: StatusCode) -> Option<KeycloakError> {
+        match status {
+            _ if status.is_success() => None,
+            StatusCode::UNAUTHORIZED => Some(KeycloakError::Unauthorized),
+            StatusCode::FORBIDDEN => Some(KeycloakError::Forbidden),
+            StatusCode::NOT_FOUND => Some(KeycloakError::NotFound),
+            _ if status.is_server_error() => Some(KeycloakError::ServerError(status)),
+            _ => Some(KeycloakError::UnexpectedStatus(status)),
+        }
+    }
+
+    /// Get the Keycloak error that caused an error, if any
+    pub fn find(err: &anyhow::Error) -> Option<&KeycloakError> {
+        err.chain()
+            .find_map(|cause| cause.downcast_ref::<KeycloakError>())
+    }
+}
+
+impl From<reqwest::Error> for KeycloakError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            KeycloakError::Timeout
+        } else {
+            KeycloakError::Network(err)
+        }
+    }
+}
+
+impl fmt::Display for KeycloakError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeycloakError::Unauthorized => write!(f, "unauthorized"),
+            KeycloakError::Forbidden => write!(f, "forbidden"),
+            KeycloakError::NotFound => write!(f, "not found"),
+            KeycloakError::ServerError(status) => write!(f, "server error: {}", status),
+            KeycloakError::UnexpectedStatus(status) => write!(f, "unexpected status: {}", status),
+            KeycloakError::Timeout => write!(f, "timed out"),
+            KeycloakError::Network(err) => write!(f, "network error: {}", err),
+            KeycloakError::CircuitOpen => {
+                write!(f, "requests suspended after repeated failures")
+            }
+            KeycloakError::OAuth(err) => write!(f, "token request rejected: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for KeycloakError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KeycloakError::Network(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------------------------
+// --- Unit tests -------------------------------------------------------------------------------
+// -----------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that status codes are classified by their meaning for the lookup
+    #[test]
+    fn test_from_status() {
+        assert!(KeycloakError::from_status(StatusCode::OK).is_none());
+        assert!(matches!(
+            KeycloakError::from_status(StatusCode::UNAUTHORIZED),
+            Some(KeycloakError::Unauthorized)
+        ));
+        assert!(matches!(
+            KeycloakError::from_status(StatusCode::FORBIDDEN),
+            Some(KeycloakError::Forbidden)
+        ));
+        assert!(matches!(
+            KeycloakError::from_status(StatusCode::NOT_FOUND),
+            Some(KeycloakError::NotFound)
+        ));
+        assert!(matches!(
+            KeycloakError::from_status(StatusCode::SERVICE_UNAVAILABLE),
+            Some(KeycloakError::ServerError(StatusCode::SERVICE_UNAVAILABLE))
+        ));
+        assert!(matches!(
+            KeycloakError::from_status(StatusCode::BAD_REQUEST),
+            Some(KeycloakError::UnexpectedStatus(StatusCode::BAD_REQUEST))
+        ));
+    }
+
+    /// Test that a Keycloak error is found behind added context
+    #[test]
+    fn test_find() {
+        let err = anyhow::Error::from(KeycloakError::Forbidden).context("Failed to list users");
+        assert!(matches!(
+            KeycloakError::find(&err),
+            Some(KeycloakError::Forbidden)
+        ));
+        assert!(KeycloakError::find(&anyhow::anyhow!("uid not found")).is_none());
+    }
+
+    /// Test that OAuth errors are parsed, and other error bodies are not
+    #[test]
+    fn test_oauth_error_from_body() {
+        let err = OAuthError::from_body(
+            r#"{"error":"invalid_client","error_description":"Invalid client credentials"}"#,
+        )
+        .unwrap();
+        assert_eq!(err.code, OAuthErrorCode::InvalidClient);
+        assert!(err.is_bad_credentials());
+        assert_eq!(
+            err.to_string(),
+            "invalid_client (Invalid client credentials)"
+        );
+        let err = OAuthError::from_body(r#"{"error":"unsupported_grant_type"}"#).unwrap();
+        assert!(!err.is_bad_credentials());
+        assert_eq!(err.to_string(), "unsupported_grant_type");
+        // errors of the admin API are not OAuth errors
+        assert!(OAuthError::from_body(r#"{"error":"HTTP 401 Unauthorized"}"#).is_none());
+        assert!(OAuthError::from_body("<html></html>").is_none());
+    }
+
+    /// Test that error codes are stored by their name in RFC 6749
+    #[test]
+    fn test_oauth_error_code_serde() {
+        assert_eq!(
+            serde_json::to_string(&OAuthErrorCode::InvalidGrant).unwrap(),
+            r#""invalid_grant""#
+        );
+        assert_eq!(
+            serde_json::from_str::<OAuthErrorCode>(r#""unauthorized_client""#).unwrap(),
+            OAuthErrorCode::UnauthorizedClient
+        );
+        assert!(serde_json::from_str::<OAuthErrorCode>(r#""unknown_error""#).is_err());
+    }
+}
@@ -3,16 +3,21 @@ use std::collections::BTreeMap;
 use anyhow::{anyhow, Result};
 use reqwest::blocking::Client;
 
-use crate::config::{KeycloakConfig, MappingConfig};
+use super::client::send;
+use super::endpoints::admin_realm_url;
+use super::error::KeycloakError;
+
+use crate::config::{GroupNaming, KeycloakConfig, MappingConfig};
 
 use super::model::{KeycloakGroupResponse, KeycloakUserResponse};
+use super::users::{get_user_id_by_name, is_visible};
 
 /// Data struct representing a group from Keycloak
 #[derive(Debug)]
-pub(crate) struct KeycloakGroup {
-    pub(crate) name: String,
-    pub(crate) gid: libc::gid_t,
-    pub(crate) members: Vec<String>,
+pub struct KeycloakGroup {
+    pub name: String,
+    pub gid: libc::gid_t,
+    pub members: Vec<String>,
 }
 
 /// Get a single attribute from a Keycloak user response.
@@ -34,132 +39,525 @@ fn get_single_attribute<'a>(
 
 /// Get Keycloak API URL for retrieving groups from Keycloak.
 fn get_groups_url(config: &KeycloakConfig) -> String {
-    format!("{}/admin/realms/{}/groups", config.url, config.realm)
+    format!("{}/groups", admin_realm_url(config))
 }
 
 /// Get the URL for retrieving members of a specific group from Keycloak.
 fn get_group_members_url(config: &KeycloakConfig, group_id: &str) -> String {
-    format!(
-        "{}/admin/realms/{}/groups/{}/members",
-        config.url, config.realm, group_id
-    )
+    format!("{}/groups/{}/members", admin_realm_url(config), group_id)
+}
+
+/// Get the URL for retrieving the subgroups of a specific group from Keycloak.
+fn get_group_children_url(config: &KeycloakConfig, group_id: &str) -> String {
+    format!("{}/groups/{}/children", admin_realm_url(config), group_id)
 }
 
-/// Send a request to retrieve the members of a specific group from Keycloak.
+/// Get the URL for retrieving a group by its path from Keycloak.
+fn get_group_by_path_url(config: &KeycloakConfig, path: &str) -> Result<reqwest::Url> {
+    let base = admin_realm_url(config);
+    let mut url = reqwest::Url::parse(&format!("{}/group-by-path", base))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("Invalid Keycloak URL {}", base))?
+        .extend(path.trim_start_matches('/').split('/'));
+    Ok(url)
+}
+
+/// Get the URL for retrieving the groups of a specific user from Keycloak.
+fn get_user_groups_url(config: &KeycloakConfig, user_id: &str) -> String {
+    format!("{}/users/{}/groups", admin_realm_url(config), user_id)
+}
+
+/// Send requests to retrieve all members of a specific group from Keycloak.
+/// Members are requested in pages of the configured page size, because
+/// Keycloak limits the number of members returned by a single request.
 fn group_member_request(
     config: &KeycloakConfig,
+    attribute_mapping: &MappingConfig,
     client: &Client,
     access_token: &str,
     group_id: &str,
 ) -> Result<Vec<String>> {
     let url = get_group_members_url(config, group_id);
-    let response = client.get(url).bearer_auth(access_token).send()?;
-    let members = serde_json::from_str::<Vec<KeycloakUserResponse>>(&response.text()?)?;
-    Ok(members.into_iter().map(|member| member.username).collect())
+    let page_size = config.page_size.get();
+    let mut members = Vec::new();
+    loop {
+        let response = send(
+            config,
+            client,
+            client
+                .get(&url)
+                .query(&[
+                    ("briefRepresentation", "true"),
+                    ("first", &members.len().to_string()),
+                    ("max", &page_size.to_string()),
+                ])
+                .bearer_auth(access_token),
+        )?;
+        let page = serde_json::from_str::<Vec<KeycloakUserResponse>>(&response.text()?)?;
+        let last_page = page.len() < page_size;
+        members.extend(page);
+        if last_page {
+            break;
+        }
+    }
+    Ok(members
+        .into_iter()
+        .filter(|member| is_visible(member, attribute_mapping))
+        .map(|member| member.username)
+        .collect())
 }
 
 /// Send a request to retrieve groups from Keycloak.
 fn groups_request(
     keycloak_config: &KeycloakConfig,
-    attribute_mapping: &MappingConfig,
     access_token: &str,
     params: &[(&str, &str)],
     client: &Client,
 ) -> Result<Vec<KeycloakGroupResponse>> {
     let url = get_groups_url(keycloak_config);
-    let mut response = client
-        .get(url)
-        .query(params)
-        .bearer_auth(access_token)
-        .send()?;
+    let response = send(
+        keycloak_config,
+        client,
+        client.get(url).query(params).bearer_auth(access_token),
+    )?;
     Ok(serde_json::from_str::<Vec<KeycloakGroupResponse>>(
         &response.text()?,
     )?)
 }
 
-fn add_group_members(
+/// Send a request to retrieve the subgroups of a specific group from Keycloak.
+fn group_children_request(
     config: &KeycloakConfig,
     client: &Client,
     access_token: &str,
-    attribute_mapping: &MappingConfig,
+    group_id: &str,
+) -> Result<Vec<KeycloakGroupResponse>> {
+    let url = get_group_children_url(config, group_id);
+    let response = send(
+        config,
+        client,
+        client
+            .get(url)
+            .query(&[
+                ("briefRepresentation", "false"),
+                ("max", &i32::MAX.to_string()),
+            ])
+            .bearer_auth(access_token),
+    )?;
+    Ok(serde_json::from_str::<Vec<KeycloakGroupResponse>>(
+        &response.text()?,
+    )?)
+}
+
+/// A group from the Keycloak group tree together with the ids of all its descendants
+struct GroupNode {
     group: KeycloakGroupResponse,
-) -> Result<KeycloakGroup> {
-    let members = group_member_request(config, &client, access_token, &group.id);
-    Ok(KeycloakGroup {
-        name: group.name,
-        gid: get_single_attribute(&group.attributes, &attribute_mapping.group_gid)?
+    descendants: Vec<String>,
+}
+
+/// Walk the given group trees and append every group as a GroupNode to `nodes`.
+/// Subgroups that are not part of the response are requested from Keycloak.
+/// Returns the ids of all groups in the given trees.
+fn flatten_groups(
+    config: &KeycloakConfig,
+    client: &Client,
+    access_token: &str,
+    groups: Vec<KeycloakGroupResponse>,
+    nodes: &mut Vec<GroupNode>,
+) -> Result<Vec<String>> {
+    let mut ids = Vec::new();
+    for mut group in groups {
+        let mut children = std::mem::take(&mut group.sub_groups);
+        if children.is_empty() && group.sub_group_count.unwrap_or(0) > 0 {
+            children = group_children_request(config, client, access_token, &group.id)?;
+        }
+        ids.push(group.id.clone());
+        let index = nodes.len();
+        nodes.push(GroupNode {
+            group,
+            descendants: Vec::new(),
+        });
+        let descendants = flatten_groups(config, client, access_token, children, nodes)?;
+        ids.extend(descendants.iter().cloned());
+        nodes[index].descendants = descendants;
+    }
+    Ok(ids)
+}
+
+/// Send a request to retrieve groups from Keycloak and flatten the group trees
+/// of the response into a list of GroupNodes.
+fn group_nodes_request(
+    config: &KeycloakConfig,
+    access_token: &str,
+    params: &[(&str, &str)],
+    client: &Client,
+) -> Result<Vec<GroupNode>> {
+    let groups = groups_request(config, access_token, params, client)?;
+    let mut nodes = Vec::new();
+    flatten_groups(config, client, access_token, groups, &mut nodes)?;
+    Ok(nodes)
+}
+
+/// Get the name of a group according to the group naming of the mapping configuration.
+fn get_group_name(group: &KeycloakGroupResponse, attribute_mapping: &MappingConfig) -> String {
+    let name = match (&attribute_mapping.group_naming, &group.path) {
+        (GroupNaming::Path, Some(path)) => path
+            .trim_start_matches('/')
+            .replace('/', &attribute_mapping.group_path_separator),
+        _ => group.name.clone(),
+    };
+    format!("{}{}", attribute_mapping.group_name_prefix, name)
+}
+
+/// Get the gid of a group. Returns an error if the group has no gid.
+fn get_group_gid(
+    group: &KeycloakGroupResponse,
+    attribute_mapping: &MappingConfig,
+) -> Result<libc::gid_t> {
+    Ok(
+        get_single_attribute(&group.attributes, &attribute_mapping.group_gid)?
             .ok_or(anyhow!(
                 "Missing required attribute {}",
                 attribute_mapping.group_gid
             ))?
             .parse()?,
-        members: members?,
+    )
+}
+
+fn add_group_members(
+    config: &KeycloakConfig,
+    client: &Client,
+    access_token: &str,
+    attribute_mapping: &MappingConfig,
+    node: GroupNode,
+) -> Result<KeycloakGroup> {
+    let gid = get_group_gid(&node.group, attribute_mapping)?;
+    let mut members = group_member_request(
+        config,
+        attribute_mapping,
+        client,
+        access_token,
+        &node.group.id,
+    )?;
+    if attribute_mapping.group_nested_members {
+        for group_id in &node.descendants {
+            for member in
+                group_member_request(config, attribute_mapping, client, access_token, group_id)?
+            {
+                if !members.contains(&member) {
+                    members.push(member);
+                }
+            }
+        }
+    }
+    Ok(KeycloakGroup {
+        name: get_group_name(&node.group, attribute_mapping),
+        gid,
+        members,
     })
 }
 
-/// List all groups from Keycloak.
-pub(crate) fn list_groups(
+/// List all groups from Keycloak, including nested groups.
+/// Groups without a valid gid are skipped.
+pub fn list_groups(
     config: &KeycloakConfig,
     attribute_mapping: &MappingConfig,
     access_token: &str,
+    client: &Client,
 ) -> Result<Vec<KeycloakGroup>> {
-    let client = Client::new();
-    Ok(groups_request(
+    group_nodes_request(
         config,
-        attribute_mapping,
         access_token,
         &[("briefRepresentation", "false")],
-        &client,
+        client,
     )?
     .into_iter()
-    .map(|group| add_group_members(config, &client, access_token, attribute_mapping, group))
-    .filter_map(|g| g.ok())
-    .collect())
+    .filter(|node| get_group_gid(&node.group, attribute_mapping).is_ok())
+    .map(|node| add_group_members(config, client, access_token, attribute_mapping, node))
+    .collect()
 }
 
-pub(crate) fn get_group_by_name(
+/// Get the term to search for in Keycloak to find a group by its (mapped) name.
+/// Returns None if the name cannot belong to a Keycloak group, e.g. because the
+/// configured group name prefix is missing.
+fn get_group_search_term<'a>(attribute_mapping: &MappingConfig, name: &'a str) -> Option<&'a str> {
+    let name = name.strip_prefix(&attribute_mapping.group_name_prefix)?;
+    match attribute_mapping.group_naming {
+        GroupNaming::Leaf => Some(name),
+        // Keycloak can only search for the name of the group itself
+        GroupNaming::Path => name
+            .rsplit(attribute_mapping.group_path_separator.as_str())
+            .next(),
+    }
+}
+
+/// Get a group by its (mapped) name
+/// Returns a KeycloakGroup instance if the group is found
+/// Returns None if the group is not found
+/// Returns an error if multiple groups with that name are found, e.g. groups
+/// with the same name in different subtrees, or any other error occurs
+pub fn get_group_by_name(
     config: &KeycloakConfig,
     attribute_mapping: &MappingConfig,
     access_token: &str,
     name: &str,
+    client: &Client,
 ) -> Result<Option<KeycloakGroup>> {
-    let client = Client::new();
-    Ok(groups_request(
+    let search = match get_group_search_term(attribute_mapping, name) {
+        Some(search) => search,
+        None => return Ok(None),
+    };
+    // the search term is the full group name only for leaf naming, otherwise
+    // the part of the name after the last separator may be part of the group name
+    let exact = match attribute_mapping.group_naming {
+        GroupNaming::Leaf => "true",
+        GroupNaming::Path => "false",
+    };
+    // Keycloak returns all groups containing the search term unless exact
+    // search is supported, and the ancestors of all matching groups,
+    // so the results are always filtered by their name.
+    let mut nodes = group_nodes_request(
         config,
-        attribute_mapping,
         access_token,
-        &[("search", name), ("briefRepresentation", "false")],
-        &client,
+        &[
+            ("search", search),
+            ("exact", exact),
+            ("briefRepresentation", "false"),
+        ],
+        client,
     )?
     .into_iter()
-    .map(|group| add_group_members(config, &client, access_token, attribute_mapping, group))
-    .filter_map(|g| g.ok())
-    .next())
+    .filter(|node| get_group_name(&node.group, attribute_mapping) == name)
+    .filter(|node| get_group_gid(&node.group, attribute_mapping).is_ok())
+    .collect::<Vec<GroupNode>>();
+    if nodes.len() > 1 {
+        return Err(anyhow!("Found more than one group with the name {}", name));
+    }
+    nodes
+        .pop()
+        .map(|node| add_group_members(config, client, access_token, attribute_mapping, node))
+        .transpose()
 }
 
-pub(crate) fn get_group_by_gid(
+/// Get a group by its gid
+/// The group is searched by Keycloak using an attribute query. Older Keycloak
+/// versions that do not support attribute queries for groups either ignore the
+/// query or reject it, in which case all groups are enumerated instead.
+/// Returns a KeycloakGroup instance if the group is found
+/// Returns None if the group is not found
+/// Returns an error if multiple groups with that gid are found or any
+/// other error occurs during the request
+pub fn get_group_by_gid(
     config: &KeycloakConfig,
     attribute_mapping: &MappingConfig,
     access_token: &str,
     gid: libc::gid_t,
+    client: &Client,
 ) -> Result<Option<KeycloakGroup>> {
-    let client = Client::new();
-    let gid_str = gid.to_string();
-    Ok(groups_request(
+    let query = format!("{}:{}", attribute_mapping.group_gid, gid);
+    let nodes = match group_nodes_request(
         config,
-        attribute_mapping,
         access_token,
-        &[("briefRepresentation", "false")],
-        &client,
-    )?
-    .into_iter()
-    .filter(
-        |group| match get_single_attribute(&group.attributes, &attribute_mapping.group_gid) {
-            Ok(Some(gid)) => *gid == gid_str,
-            _ => false,
-        },
-    )
-    .map(|group| add_group_members(config, &client, access_token, attribute_mapping, group))
-    .filter_map(|g| g.ok())
-    .next())
+        &[
+            ("q", &query),
+            ("exact", "true"),
+            ("briefRepresentation", "false"),
+        ],
+        client,
+    ) {
+        Ok(nodes) => nodes,
+        // older Keycloak versions reject the attribute query as a bad request
+        Err(err)
+            if matches!(
+                KeycloakError::find(&err),
+                Some(KeycloakError::UnexpectedStatus(status)) if status.is_client_error()
+            ) =>
+        {
+            log::warn!(
+                "Failed to query groups by attribute, enumerating all groups instead: {:?}",
+                err
+            );
+            group_nodes_request(
+                config,
+                access_token,
+                &[("briefRepresentation", "false")],
+                client,
+            )?
+        }
+        Err(err) => return Err(err),
+    };
+    // the query result contains the ancestors of all matching groups,
+    // and all groups if the query is not supported
+    let mut nodes = nodes
+        .into_iter()
+        .filter(|node| {
+            matches!(get_group_gid(&node.group, attribute_mapping), Ok(group_gid) if group_gid == gid)
+        })
+        .collect::<Vec<GroupNode>>();
+    if nodes.len() > 1 {
+        return Err(anyhow!("Found more than one group with the gid {}", gid));
+    }
+    nodes
+        .pop()
+        .map(|node| add_group_members(config, client, access_token, attribute_mapping, node))
+        .transpose()
+}
+
+/// Get the paths of all ancestors of a group path, e.g. `/eng` for `/eng/platform`.
+fn get_ancestor_paths(path: &str) -> Vec<&str> {
+    path.match_indices('/')
+        .skip(1)
+        .map(|(index, _)| &path[..index])
+        .collect()
+}
+
+/// Send a request to retrieve a group by its path from Keycloak.
+fn group_by_path_request(
+    config: &KeycloakConfig,
+    client: &Client,
+    access_token: &str,
+    path: &str,
+) -> Result<KeycloakGroupResponse> {
+    let url = get_group_by_path_url(config, path)?;
+    let response = send(config, client, client.get(url).bearer_auth(access_token))?;
+    Ok(serde_json::from_str::<KeycloakGroupResponse>(
+        &response.text()?,
+    )?)
+}
+
+/// Get all groups a user is a member of, using a single request for the user's
+/// group memberships instead of enumerating all groups. If nested members are
+/// enabled, the ancestors of these groups are included as well.
+/// The members of the returned groups are not populated.
+/// Returns None if the user is not found.
+pub fn get_groups_by_user(
+    config: &KeycloakConfig,
+    attribute_mapping: &MappingConfig,
+    access_token: &str,
+    username: &str,
+    client: &Client,
+) -> Result<Option<Vec<KeycloakGroup>>> {
+    let user_id =
+        match get_user_id_by_name(config, attribute_mapping, access_token, username, client)? {
+            Some(user_id) => user_id,
+            None => return Ok(None),
+        };
+    let response = send(
+        config,
+        client,
+        client
+            .get(get_user_groups_url(config, &user_id))
+            .query(&[("briefRepresentation", "false")])
+            .bearer_auth(access_token),
+    )?;
+    let mut groups = serde_json::from_str::<Vec<KeycloakGroupResponse>>(&response.text()?)?;
+    if attribute_mapping.group_nested_members {
+        // members of a subgroup are also members of all ancestors of that subgroup
+        let mut paths: Vec<String> = groups.iter().filter_map(|g| g.path.clone()).collect();
+        for path in paths.clone() {
+            for ancestor in get_ancestor_paths(&path) {
+                if !paths.iter().any(|known| known == ancestor) {
+                    paths.push(ancestor.to_string());
+                    groups.push(group_by_path_request(
+                        config,
+                        client,
+                        access_token,
+                        ancestor,
+                    )?);
+                }
+            }
+        }
+    }
+    Ok(Some(
+        groups
+            .iter()
+            .filter_map(|group| match get_group_gid(group, attribute_mapping) {
+                Ok(gid) => Some(KeycloakGroup {
+                    name: get_group_name(group, attribute_mapping),
+                    gid,
+                    members: vec![],
+                }),
+                _ => None,
+            })
+            .collect(),
+    ))
+}
+
+// -----------------------------------------------------------------------------------------------
+// --- Unit tests -------------------------------------------------------------------------------
+// -----------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    /// Build a mapping configuration with the given group naming options
+    fn mapping(group_options: &str) -> MappingConfig {
+        test_config(r#"url = "https://sso.example.com""#, group_options).mapping
+    }
+
+    fn group(name: &str, path: &str) -> KeycloakGroupResponse {
+        KeycloakGroupResponse {
+            id: "id".to_string(),
+            name: name.to_string(),
+            path: Some(path.to_string()),
+            attributes: None,
+            sub_groups: vec![],
+            sub_group_count: None,
+        }
+    }
+
+    /// Test that nested groups are named according to the naming configuration
+    #[test]
+    fn test_get_group_name() {
+        let group = group("platform", "/eng/platform");
+        assert_eq!(get_group_name(&group, &mapping("")), "platform");
+        assert_eq!(
+            get_group_name(&group, &mapping(r#"group_naming = "path""#)),
+            "eng-platform"
+        );
+        assert_eq!(
+            get_group_name(
+                &group,
+                &mapping(
+                    r#"
+                    group_naming = "path"
+                    group_path_separator = "_"
+                    group_name_prefix = "kc-"
+                    "#
+                )
+            ),
+            "kc-eng_platform"
+        );
+    }
+
+    /// Test that the search term for a group name is the name of the group itself
+    #[test]
+    fn test_get_group_search_term() {
+        let path_mapping = mapping(
+            r#"
+            group_naming = "path"
+            group_name_prefix = "kc-"
+            "#,
+        );
+        assert_eq!(
+            get_group_search_term(&mapping(""), "platform"),
+            Some("platform")
+        );
+        assert_eq!(
+            get_group_search_term(&path_mapping, "kc-eng-platform"),
+            Some("platform")
+        );
+        assert_eq!(get_group_search_term(&path_mapping, "eng-platform"), None);
+    }
+
+    /// Test that all ancestors of a group path are found
+    #[test]
+    fn test_get_ancestor_paths() {
+        assert_eq!(
+            get_ancestor_paths("/eng/platform/core"),
+            vec!["/eng", "/eng/platform"]
+        );
+        assert!(get_ancestor_paths("/eng").is_empty());
+    }
 }
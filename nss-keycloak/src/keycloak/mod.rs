@@ -1,4 +1,8 @@
 pub mod auth;
+pub mod client;
+pub(crate) mod endpoints;
+pub mod error;
 pub mod groups;
 mod model;
+pub(crate) mod tls;
 pub mod users;
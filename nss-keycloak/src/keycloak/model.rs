@@ -2,14 +2,22 @@ use std::collections::BTreeMap;
 
 #[derive(Debug, serde::Deserialize)]
 pub(super) struct KeycloakUserResponse {
+    pub(super) id: String,
     pub(super) username: String,
     pub(super) enabled: bool,
     pub(super) attributes: Option<BTreeMap<String, Vec<String>>>,
 }
 
 #[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub(super) struct KeycloakGroupResponse {
     pub(super) id: String,
     pub(super) name: String,
+    pub(super) path: Option<String>,
     pub(super) attributes: Option<BTreeMap<String, Vec<String>>>,
+    // subgroups may be omitted by newer Keycloak versions, which only
+    // return the number of subgroups and require a request for the children
+    #[serde(default)]
+    pub(super) sub_groups: Vec<KeycloakGroupResponse>,
+    pub(super) sub_group_count: Option<u64>,
 }
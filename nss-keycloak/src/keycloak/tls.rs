@@ -0,0 +1,271 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context, Result};
+use ring::digest::{digest, SHA256};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName};
+
+use crate::config::{parse_fingerprint, secret_path, KeycloakConfig, TlsVersion};
+
+/// Read all certificates of a PEM file. Certificates are public, so unlike keys
+/// a relative path is not taken from the systemd credentials directory.
+fn read_certificates(file: &str, description: &str) -> Result<Vec<Certificate>> {
+    let path = Path::new(file);
+    let pem = std::fs::read(path)
+        .with_context(|| format!("Failed to read {} {}", description, path.display()))?;
+    let certificates: Vec<Certificate> = rustls_pemfile::certs(&mut pem.as_slice())
+        .with_context(|| format!("Failed to parse {} {}", description, path.display()))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certificates.is_empty() {
+        return Err(anyhow!(
+            "{} {} contains no PEM certificate",
+            description,
+            path.display()
+        ));
+    }
+    Ok(certificates)
+}
+
+/// Read the first private key of a PEM file, in PKCS#8, PKCS#1 (RSA) or SEC1 (EC) format
+fn read_private_key(file: &str) -> Result<PrivateKey> {
+    let path = secret_path(file)?;
+    let pem = std::fs::read(&path)
+        .with_context(|| format!("Failed to read client key {}", path.display()))?;
+    let items = rustls_pemfile::read_all(&mut pem.as_slice())
+        .with_context(|| format!("Failed to parse client key {}", path.display()))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("client key {} contains no PEM private key", path.display()))
+}
+
+/// Read the client certificate and key for mutual TLS
+fn read_identity(cert_file: &str, key_file: &str) -> Result<(Vec<Certificate>, PrivateKey)> {
+    Ok((
+        read_certificates(cert_file, "client certificate")?,
+        read_private_key(key_file)?,
+    ))
+}
+
+// id of the process that logged the warning about `tls_insecure`, which is
+// logged once per process, also in processes forked after it was logged
+static INSECURE_WARNED: AtomicU32 = AtomicU32::new(0);
+
+// the system trust store, loaded once per process: reading and parsing it takes long,
+// and a client is built again after every fork
+static SYSTEM_ROOTS: OnceLock<RootCertStore> = OnceLock::new();
+
+/// Load the CA certificates of the system trust store
+fn system_roots() -> Result<RootCertStore> {
+    if let Some(roots) = SYSTEM_ROOTS.get() {
+        return Ok(roots.clone());
+    }
+    let certificates: Vec<Certificate> = rustls_native_certs::load_native_certs()
+        .context("Failed to load the system trust store")?
+        .into_iter()
+        .map(|certificate| Certificate(certificate.0))
+        .collect();
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(&certificates);
+    if added == 0 {
+        log::warn!("The system trust store contains no CA certificates");
+    }
+    Ok(SYSTEM_ROOTS.get_or_init(|| roots).clone())
+}
+
+/// Trusted CA certificates, from the CA file if configured or else the system trust store
+fn root_store(config: &KeycloakConfig) -> Result<RootCertStore> {
+    let ca_file = match &config.ca_file {
+        Some(ca_file) => ca_file,
+        None => return system_roots(),
+    };
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(&read_certificates(ca_file, "CA file")?);
+    if added == 0 {
+        return Err(anyhow!("CA file {} contains no valid CA", ca_file));
+    }
+    Ok(roots)
+}
+
+/// Verifies the server certificate like the default verifier, and additionally
+/// that it is the pinned certificate
+struct PinnedCertVerifier {
+    verifier: WebPkiVerifier,
+    fingerprint: Vec<u8>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        if digest(&SHA256, &end_entity.0).as_ref() != self.fingerprint.as_slice() {
+            return Err(rustls::Error::General(
+                "server certificate does not match keycloak.tls_pin_sha256".to_string(),
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Accepts any server certificate, for `tls_insecure`
+struct InsecureCertVerifier;
+
+impl ServerCertVerifier for InsecureCertVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Log loudly that the certificate of Keycloak is not verified, once per process.
+/// It goes to syslog in the processes loading the plugin, which have no logger.
+fn warn_insecure(config: &KeycloakConfig) {
+    if INSECURE_WARNED.swap(std::process::id(), Ordering::Relaxed) == std::process::id() {
+        return;
+    }
+    crate::syslog::init();
+    log::error!(
+        "keycloak.tls_insecure is set: the certificate of {} is NOT verified, \
+         any attacker on the network can read the credentials. Never use this in production.",
+        config.url
+    );
+}
+
+/// Build the TLS configuration for connections to Keycloak: trusted CAs, certificate pin,
+/// minimum TLS version and the client certificate for mutual TLS
+pub fn client_config(config: &KeycloakConfig) -> Result<ClientConfig> {
+    let versions: &[&rustls::SupportedProtocolVersion] = match config.tls_min_version {
+        TlsVersion::Tls12 => &[&rustls::version::TLS13, &rustls::version::TLS12],
+        TlsVersion::Tls13 => &[&rustls::version::TLS13],
+    };
+    let verifier: Arc<dyn ServerCertVerifier> = if config.tls_insecure {
+        warn_insecure(config);
+        Arc::new(InsecureCertVerifier)
+    } else {
+        let verifier = WebPkiVerifier::new(root_store(config)?, None);
+        match &config.tls_pin_sha256 {
+            Some(fingerprint) => Arc::new(PinnedCertVerifier {
+                verifier,
+                fingerprint: parse_fingerprint(fingerprint)?,
+            }),
+            None => Arc::new(verifier),
+        }
+    };
+    let builder = ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)?
+        .with_custom_certificate_verifier(verifier);
+    Ok(match (&config.tls_client_cert, &config.tls_client_key) {
+        (Some(cert), Some(key)) => {
+            let (certificates, key) = read_identity(cert, key)?;
+            builder
+                .with_client_auth_cert(certificates, key)
+                .context("Failed to use the client certificate with its key")?
+        }
+        _ => builder.with_no_client_auth(),
+    })
+}
+
+// -----------------------------------------------------------------------------------------------
+// --- Unit tests -------------------------------------------------------------------------------
+// -----------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_keycloak_config;
+
+    const CERT_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/files/client-cert.pem");
+    const KEY_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/files/client-key.pem");
+
+    /// Test that a client certificate with its key is loaded
+    #[test]
+    fn test_read_identity() {
+        assert!(read_identity(CERT_FILE, KEY_FILE).is_ok());
+        let err = read_identity(CERT_FILE, "/nonexistent/client-key.pem").unwrap_err();
+        assert!(format!("{:#}", err).contains("/nonexistent/client-key.pem"));
+        // a certificate is not a key
+        let err = read_identity(CERT_FILE, CERT_FILE).unwrap_err();
+        assert!(err.to_string().contains("contains no PEM private key"));
+    }
+
+    /// Test that the CA file, the client certificate and its key are loaded
+    #[test]
+    fn test_client_config() {
+        let config = test_keycloak_config(&format!(
+            r#"
+            ca_file = "{}"
+            tls_client_cert = "{}"
+            tls_client_key = "{}"
+            tls_min_version = "1.3"
+            "#,
+            CERT_FILE, CERT_FILE, KEY_FILE
+        ));
+        assert!(client_config(&config).is_ok());
+
+        // a certificate is not a key
+        let config = KeycloakConfig {
+            tls_client_key: Some(CERT_FILE.to_string()),
+            ..config
+        };
+        let err = client_config(&config).unwrap_err();
+        assert!(err.to_string().contains("contains no PEM private key"));
+
+        let config = KeycloakConfig {
+            ca_file: Some(KEY_FILE.to_string()),
+            ..config
+        };
+        let err = client_config(&config).unwrap_err();
+        assert!(err.to_string().contains("contains no PEM certificate"));
+    }
+
+    /// Test that relative paths of keys are taken from the systemd credentials directory,
+    /// and those of certificates from the working directory
+    #[test]
+    fn test_relative_paths() {
+        let files = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/files");
+        temp_env::with_var("CREDENTIALS_DIRECTORY", Some(files), || {
+            assert!(read_identity("tests/files/client-cert.pem", "client-key.pem").is_ok());
+            assert!(read_certificates("client-cert.pem", "CA file").is_err());
+        });
+    }
+
+    /// Test that the system trust store is loaded once and reused by later clients
+    #[test]
+    fn test_system_roots() {
+        let roots = system_roots().unwrap();
+        assert_eq!(SYSTEM_ROOTS.get().unwrap().len(), roots.len());
+        assert_eq!(system_roots().unwrap().len(), roots.len());
+    }
+}
@@ -3,11 +3,11 @@ use std::collections::BTreeMap;
 use anyhow::{anyhow, Ok, Result};
 use reqwest::blocking::Client;
 
-use super::model::KeycloakUserResponse;
-use crate::config::{KeycloakConfig, MappingConfig};
+use super::client::send;
+use super::endpoints::admin_realm_url;
 
-/// batch size for the Keycloak user list API
-const BATCH_SIZE: usize = 100;
+use super::model::KeycloakUserResponse;
+use crate::config::{DisabledUserPolicy, KeycloakConfig, MappingConfig};
 
 /// Data struct for a Keycloak user
 #[derive(Debug)]
@@ -18,6 +18,20 @@ pub struct KeycloakUser {
     pub homedir: String,
     pub loginshell: String,
     pub gecos: String,
+    pub enabled: bool,
+    pub password_aging: PasswordAging,
+}
+
+/// Password aging information of a Keycloak user, as used by the shadow database.
+/// All values are in days, `None` if the corresponding attribute is not mapped or not set.
+#[derive(Debug, Default)]
+pub struct PasswordAging {
+    pub last_change: Option<i64>,
+    pub min_days: Option<i64>,
+    pub max_days: Option<i64>,
+    pub warn_days: Option<i64>,
+    pub inactive_days: Option<i64>,
+    pub expire: Option<i64>,
 }
 
 struct MappedKeycloakUserResponse<'a> {
@@ -76,6 +90,36 @@ impl MappedKeycloakUserResponse<'_> {
     fn get_user_gid(&self) -> Result<Option<&String>> {
         get_single_attribute(&self.response.attributes, &self.mapping.user_gid)
     }
+
+    /// Get an optional password aging attribute and parse it as number of days.
+    /// Aging is only used by the shadow database, so an invalid value is logged and
+    /// ignored instead of hiding the user from passwd and group lookups.
+    fn get_password_aging_days(&self, attr_name: &Option<String>) -> Option<i64> {
+        let attr_name = attr_name.as_ref()?;
+        let result = get_single_attribute(&self.response.attributes, attr_name)
+            .and_then(|value| Ok(value.map(|value| value.parse()).transpose()?));
+        result.unwrap_or_else(|err| {
+            log::warn!(
+                "Ignoring attribute {} of user {}: {}",
+                attr_name,
+                self.response.username,
+                err
+            );
+            None
+        })
+    }
+
+    /// Get the user's password aging information
+    fn get_password_aging(&self) -> PasswordAging {
+        PasswordAging {
+            last_change: self.get_password_aging_days(&self.mapping.shadow_last_change),
+            min_days: self.get_password_aging_days(&self.mapping.shadow_min_days),
+            max_days: self.get_password_aging_days(&self.mapping.shadow_max_days),
+            warn_days: self.get_password_aging_days(&self.mapping.shadow_warn_days),
+            inactive_days: self.get_password_aging_days(&self.mapping.shadow_inactive_days),
+            expire: self.get_password_aging_days(&self.mapping.shadow_expire),
+        }
+    }
 }
 
 /// Implement TryFrom for KeycloakUser to allow conversion from MappedKeycloakUserResponse
@@ -93,23 +137,36 @@ impl TryFrom<MappedKeycloakUserResponse<'_>> for KeycloakUser {
         let homedir = value.get_user_home()?;
         let loginshell = value.get_user_shell()?;
         let gecos = value.get_user_gecos()?;
+        let password_aging = value.get_password_aging();
+        // disabled users must not be usable for logins, whatever their configured shell
+        let loginshell = match value.response.enabled {
+            true => loginshell.unwrap_or(&default_loginshell),
+            false => &value.mapping.disabled_user_shell,
+        };
         Ok(KeycloakUser {
             username: value.response.username.to_owned(),
             uid: uid.ok_or(anyhow!("uid not found"))?.parse()?,
             gid: gid.ok_or(anyhow!("gid not found"))?.parse()?,
-            homedir: homedir.or(Some(&default_homedir)).unwrap().to_owned(),
-            loginshell: loginshell.or(Some(&default_loginshell)).unwrap().to_owned(),
-            gecos: gecos.or(Some(&default_gecos)).unwrap().to_owned(),
+            homedir: homedir.unwrap_or(&default_homedir).to_owned(),
+            loginshell: loginshell.to_owned(),
+            gecos: gecos.unwrap_or(&default_gecos).to_owned(),
+            enabled: value.response.enabled,
+            password_aging,
         })
     }
 }
 
+/// Check if a user is visible according to the disabled user policy of the mapping
+pub(super) fn is_visible(response: &KeycloakUserResponse, mapping: &MappingConfig) -> bool {
+    response.enabled || mapping.disabled_users != DisabledUserPolicy::Hide
+}
+
 fn get_users_api_url(config: &KeycloakConfig) -> String {
-    format!("{}/admin/realms/{}/users", config.url, config.realm)
+    format!("{}/users", admin_realm_url(config))
 }
 
 fn get_user_count_api_url(config: &KeycloakConfig) -> String {
-    format!("{}/admin/realms/{}/users/count", config.url, config.realm)
+    format!("{}/users/count", admin_realm_url(config))
 }
 
 fn get_number_of_users(
@@ -117,10 +174,13 @@ fn get_number_of_users(
     access_token: &str,
     client: &Client,
 ) -> Result<libc::uid_t> {
-    let response = client
-        .get(get_user_count_api_url(config))
-        .bearer_auth(access_token)
-        .send()?;
+    let response = send(
+        config,
+        client,
+        client
+            .get(get_user_count_api_url(config))
+            .bearer_auth(access_token),
+    )?;
     Ok(response.text()?.parse()?)
 }
 
@@ -135,17 +195,21 @@ fn users_request(
     query_args: &[(&str, &str)],
     client: &Client,
 ) -> Result<Vec<KeycloakUser>> {
-    let response = client
-        .get(get_users_api_url(config))
-        .bearer_auth(access_token)
-        .query(query_args)
-        .send()?;
+    let response = send(
+        config,
+        client,
+        client
+            .get(get_users_api_url(config))
+            .bearer_auth(access_token)
+            .query(query_args),
+    )?;
     let users: Vec<KeycloakUserResponse> = serde_json::from_str(&response.text()?)?;
 
     Ok(users
         .iter()
-        .map(|user| MappedKeycloakUserResponse::new(&user, attribute_mapping))
-        .map(|mapped_user| KeycloakUser::try_from(mapped_user))
+        .filter(|user| is_visible(user, attribute_mapping))
+        .map(|user| MappedKeycloakUserResponse::new(user, attribute_mapping))
+        .map(KeycloakUser::try_from)
         .filter_map(|res| res.ok())
         .collect::<Vec<KeycloakUser>>())
 }
@@ -157,11 +221,12 @@ pub fn list_users(
     config: &KeycloakConfig,
     attribute_mapping: &MappingConfig,
     access_token: &str,
+    client: &Client,
 ) -> Result<Vec<KeycloakUser>> {
-    let client = Client::new();
-    let nusers = get_number_of_users(config, access_token, &client)?;
+    let nusers = get_number_of_users(config, access_token, client)?;
     let mut users = Vec::with_capacity(nusers as usize);
-    for first in (0..nusers).step_by(BATCH_SIZE) {
+    let page_size = config.page_size.get();
+    for first in (0..nusers).step_by(page_size) {
         users.append(&mut users_request(
             config,
             attribute_mapping,
@@ -169,9 +234,9 @@ pub fn list_users(
             &[
                 ("briefRepresentation", "false"),
                 ("first", &format!("{}", first)),
-                ("max", &format!("{}", BATCH_SIZE)),
+                ("max", &format!("{}", page_size)),
             ],
-            &client,
+            client,
         )?);
     }
     Ok(users)
@@ -187,14 +252,14 @@ pub fn get_user_by_name(
     attribute_mapping: &MappingConfig,
     access_token: &str,
     username: &str,
+    client: &Client,
 ) -> Result<Option<KeycloakUser>> {
-    let client = Client::new();
     let mut users = users_request(
         config,
         attribute_mapping,
         access_token,
         &[("username", username), ("exact", "true")],
-        &client,
+        client,
     )?;
     if users.len() <= 1 {
         Ok(users.pop())
@@ -206,6 +271,41 @@ pub fn get_user_by_name(
     }
 }
 
+/// Get the Keycloak id of a user by its username
+/// Returns None if the user is not found
+/// Returns an error if multiple users with that name are found or any
+/// other error occurs during the request
+pub(super) fn get_user_id_by_name(
+    config: &KeycloakConfig,
+    attribute_mapping: &MappingConfig,
+    access_token: &str,
+    username: &str,
+    client: &Client,
+) -> Result<Option<String>> {
+    let response = send(
+        config,
+        client,
+        client
+            .get(get_users_api_url(config))
+            .bearer_auth(access_token)
+            .query(&[
+                ("username", username),
+                ("exact", "true"),
+                ("briefRepresentation", "true"),
+            ]),
+    )?;
+    let mut users: Vec<KeycloakUserResponse> = serde_json::from_str(&response.text()?)?;
+    users.retain(|user| is_visible(user, attribute_mapping));
+    if users.len() <= 1 {
+        Ok(users.pop().map(|user| user.id))
+    } else {
+        Err(anyhow!(
+            "Found more than one user with the name {}",
+            username
+        ))
+    }
+}
+
 /// Get a user by its uid
 /// Returns a KeycloakUser instance if the user is found
 /// Returns None if the user is not found
@@ -215,8 +315,8 @@ pub fn get_user_by_uid(
     attribute_mapping: &MappingConfig,
     access_token: &str,
     uid: libc::uid_t,
+    client: &Client,
 ) -> Result<Option<KeycloakUser>> {
-    let client = Client::new();
     let mut users = users_request(
         config,
         attribute_mapping,
@@ -226,7 +326,7 @@ pub fn get_user_by_uid(
             ("q", &format!("{}:{}", attribute_mapping.user_uid, uid)),
             ("exact", "true"),
         ],
-        &client,
+        client,
     )?;
     if users.len() <= 1 {
         Ok(users.pop())
@@ -234,3 +334,44 @@ pub fn get_user_by_uid(
         Err(anyhow!("Found more than one user with the uid {}", uid))
     }
 }
+
+// -----------------------------------------------------------------------------------------------
+// --- Unit tests -------------------------------------------------------------------------------
+// -----------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    /// Test that an invalid password aging attribute does not hide the user
+    #[test]
+    fn test_invalid_password_aging() {
+        let mapping = test_config(
+            r#"url = "https://sso.example.com""#,
+            "shadow_max_days = \"passwordmaxdays\"\nshadow_warn_days = \"passwordwarndays\"",
+        )
+        .mapping;
+        let attributes = [
+            ("uidnumber", "1000"),
+            ("gidnumber", "500"),
+            ("passwordmaxdays", "ninety"),
+            ("passwordwarndays", "7"),
+        ];
+        let response = KeycloakUserResponse {
+            id: "id".to_string(),
+            username: "user01".to_string(),
+            enabled: true,
+            attributes: Some(
+                attributes
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), vec![value.to_string()]))
+                    .collect(),
+            ),
+        };
+        let user =
+            KeycloakUser::try_from(MappedKeycloakUserResponse::new(&response, &mapping)).unwrap();
+        assert_eq!(user.uid, 1000);
+        assert_eq!(user.password_aging.max_days, None);
+        assert_eq!(user.password_aging.warn_days, Some(7));
+    }
+}
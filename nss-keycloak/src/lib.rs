@@ -1,9 +1,24 @@
+mod cache;
 pub mod config;
+pub mod daemon;
+mod disk_cache;
 mod group;
+mod initgroups;
 pub mod keycloak;
 mod passwd;
+mod shadow;
+mod syslog;
 
-use std::sync::Mutex;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, MutexGuard, Once, PoisonError};
+
+use anyhow::{anyhow, Context};
+
+use libnss::interop::Response;
+use reqwest::blocking::Client;
+
+use keycloak::auth::TokenProvider;
+use keycloak::error::KeycloakError;
 
 #[macro_use]
 extern crate lazy_static;
@@ -12,19 +27,216 @@ extern crate lazy_static;
 extern crate libnss;
 
 pub use group::KeycloakNssGroup;
+pub use initgroups::KeycloakNssInitgroups;
 pub use passwd::KeycloakNssPasswd;
+pub use shadow::KeycloakNssShadow;
+
+// set once the error of loading the configuration has been logged
+static CONFIG_ERROR_LOGGED: Once = Once::new();
 
 lazy_static! {
+    // Initialization errors are kept instead of panicking, as the globals are
+    // initialized inside the NSS hooks of arbitrary processes.
     // TODO: Remove pub visibility once the plugin is implemented
-    pub static ref CONFIG: config::Config = config::load_config()
-        .expect("Failed to load plugin configuration");
+    pub static ref CONFIG: anyhow::Result<config::Config> = config::load_config()
+        .context("Failed to load plugin configuration");
+
+    pub static ref HTTP_CLIENT: anyhow::Result<keycloak::client::HttpClient<'static>> =
+        initialized(&CONFIG)
+            .map(|config| keycloak::client::HttpClient::new(&config.keycloak));
+
+    pub static ref AUTH: anyhow::Result<Mutex<keycloak::auth::KeycloakAuth<'static>>> =
+        initialized(&CONFIG).and_then(|config| {
+            let client = initialized(&HTTP_CLIENT)?;
+            let auth = keycloak::auth::KeycloakAuth::new(&config.keycloak, client)
+                .context("Failed to initialize Keycloak authentication")?
+                .with_state_directory(config.cache.directory.as_deref());
+            Ok(Mutex::new(auth))
+        });
+
+    static ref CACHE: cache::Cache = cache::Cache::new();
+}
+
+/// Get the value of a global, or an error if its initialization failed
+fn initialized<T>(global: &'static anyhow::Result<T>) -> anyhow::Result<&'static T> {
+    global.as_ref().map_err(|err| anyhow!("{:#}", err))
+}
+
+/// Lock the token provider. A panic while it was locked does not leave the token
+/// in an inconsistent state, so a poisoned lock is used as is.
+fn lock_auth() -> anyhow::Result<MutexGuard<'static, keycloak::auth::KeycloakAuth<'static>>> {
+    Ok(initialized(&AUTH)?
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner))
+}
+
+/// Get the shared HTTP client and a valid access token for requests to Keycloak
+fn keycloak_session() -> anyhow::Result<(Client, String)> {
+    let client = initialized(&HTTP_CLIENT)?.get()?;
+    let access_token = lock_auth()?.get_access_token()?.clone();
+    Ok((client, access_token))
+}
+
+/// Run the whole body of an NSS hook, including the daemon query and the caches.
+/// Errors are logged to syslog, unless the process has a logger of its own.
+/// Returns Response::Unavail if it panics, as a panic must never unwind into the
+/// calling C code.
+fn catch_panic<T>(request: &daemon::Request, hook: impl FnOnce() -> Response<T>) -> Response<T> {
+    syslog::init();
+    panic::catch_unwind(AssertUnwindSafe(hook)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        log::error!(
+            "Failed to resolve {:?}: the lookup panicked: {}",
+            request,
+            message
+        );
+        Response::Unavail
+    })
+}
+
+/// Run a Keycloak lookup for an NSS hook and convert its result into an NSS response.
+/// The lookup gets the configuration, the shared HTTP client and a valid access token,
+/// and all of its requests are limited by the configured lookup timeout. If Keycloak
+/// rejects the access token, the token is refreshed and the lookup is retried once.
+/// Returns Response::Unavail if the plugin is not configured correctly.
+fn lookup<T>(
+    description: &str,
+    lookup: impl Fn(&config::Config, &Client, &str) -> anyhow::Result<Option<T>>,
+) -> Response<T> {
+    let config = match initialized(&CONFIG) {
+        Ok(config) => config,
+        Err(err) => {
+            // the configuration is only loaded once, so the error does not change
+            CONFIG_ERROR_LOGGED.call_once(|| log::error!("Failed to {}: {:?}", description, err));
+            return Response::Unavail;
+        }
+    };
+    keycloak::client::with_deadline(&config.keycloak, || {
+        let run = || {
+            keycloak_session()
+                .and_then(|(client, access_token)| lookup(config, &client, &access_token))
+        };
+        let result = match run() {
+            Err(err) if matches!(KeycloakError::find(&err), Some(KeycloakError::Unauthorized)) => {
+                log::warn!("Keycloak rejected the access token, retrying: {:?}", err);
+                lock_auth()
+                    .map(|mut auth| auth.invalidate_access_token())
+                    .and_then(|_| run())
+            }
+            result => result,
+        };
+        match result {
+            Ok(Some(entry)) => Response::Success(entry),
+            Ok(None) => Response::NotFound,
+            Err(err) => {
+                log::error!("Failed to {}: {:?}", description, err);
+                error_response(&err)
+            }
+        }
+    })
+}
+
+/// Run a lookup of a single entry through the cache.
+/// Cached results are returned without asking Keycloak, and found as well as
+/// missing entries are cached with their configured TTL. If the lookup fails,
+/// the entry is served from the persistent cache if available. The persistent
+/// cache is only written by enumerations, see `cached_enumeration`.
+fn cached_lookup<T: disk_cache::Persistent>(
+    description: &str,
+    cache: &cache::Entries<T>,
+    key: cache::Key,
+    lookup: impl Fn(&config::Config, &Client, &str) -> anyhow::Result<Option<T>>,
+) -> Response<T> {
+    match cache.get(key) {
+        Some(Some(entry)) => return Response::Success(entry),
+        Some(None) => return Response::NotFound,
+        None => {}
+    }
+    let response = crate::lookup(description, lookup);
+    let Ok(config) = initialized(&CONFIG) else {
+        return response;
+    };
+    match response {
+        Response::Success(entry) => {
+            cache.insert(&config.cache, key, Some(entry.clone()));
+            Response::Success(entry)
+        }
+        Response::NotFound => {
+            cache.insert(&config.cache, key, None);
+            Response::NotFound
+        }
+        response => match disk_cache::get(&config.cache, key) {
+            Some(entry) => {
+                log::warn!(
+                    "Serving stale entry from the persistent cache to {}",
+                    description
+                );
+                Response::Success(entry)
+            }
+            None => response,
+        },
+    }
+}
+
+/// Run an enumeration of all entries and cache them for lookups by name and id.
+/// The persistent cache is replaced with the complete result, so that concurrent
+/// writers cannot lose each other's updates. If the enumeration fails, the entries
+/// are served from the persistent cache if available.
+fn cached_enumeration<T: disk_cache::Persistent>(
+    description: &str,
+    cache: &cache::Entries<T>,
+    lookup: impl Fn(&config::Config, &Client, &str) -> anyhow::Result<Option<Vec<T>>>,
+) -> Response<Vec<T>> {
+    let response = crate::lookup(description, lookup);
+    let Ok(config) = initialized(&CONFIG) else {
+        return response;
+    };
+    match response {
+        Response::Success(entries) => {
+            cache.insert_all(&config.cache, entries.iter().cloned());
+            disk_cache::replace_all(&config.cache, &entries);
+            Response::Success(entries)
+        }
+        response => match disk_cache::get_all(&config.cache) {
+            Some(entries) => {
+                log::warn!(
+                    "Serving stale entries from the persistent cache to {}",
+                    description
+                );
+                Response::Success(entries)
+            }
+            None => response,
+        },
+    }
+}
 
-    pub static ref AUTH: Mutex<keycloak::auth::KeycloakAuth<'static>> =
-        Mutex::new(
-            keycloak::auth::KeycloakAuth::new(&CONFIG.keycloak)
-                .expect("Failed to initialize Keycloak authentication")
-            );
+/// Get the NSS response for a failed lookup
+/// Returns Response::NotFound if Keycloak does not know the requested resource
+/// Returns Response::Unavail if Keycloak cannot be reached, did not answer in time,
+/// failed repeatedly, or denies access or rejects the credentials, which retrying
+/// will not fix
+/// Returns Response::TryAgain for server errors and any other error
+fn error_response<T>(err: &anyhow::Error) -> Response<T> {
+    match KeycloakError::find(err) {
+        Some(KeycloakError::NotFound) => Response::NotFound,
+        Some(
+            KeycloakError::Unauthorized
+            | KeycloakError::Forbidden
+            | KeycloakError::Timeout
+            | KeycloakError::Network(_)
+            | KeycloakError::CircuitOpen
+            | KeycloakError::OAuth(_),
+        ) => Response::Unavail,
+        _ if keycloak::client::is_timeout(err) => Response::Unavail,
+        _ => Response::TryAgain,
+    }
 }
 
 libnss_group_hooks!(keycloak, KeycloakNssGroup);
+libnss_initgroups_hooks!(keycloak, KeycloakNssInitgroups);
 libnss_passwd_hooks!(keycloak, KeycloakNssPasswd);
+libnss_shadow_hooks!(keycloak, KeycloakNssShadow);
@@ -1,7 +1,8 @@
 use libnss::interop::Response;
 use libnss::passwd::{Passwd, PasswdHooks};
 
-use crate::keycloak::auth::TokenProvider;
+use crate::cache::Key;
+use crate::daemon::Request;
 use crate::keycloak::users::{get_user_by_name, get_user_by_uid, list_users, KeycloakUser};
 
 pub struct KeycloakNssPasswd;
@@ -21,72 +22,57 @@ impl From<KeycloakUser> for Passwd {
 }
 
 impl PasswdHooks for KeycloakNssPasswd {
-    fn get_all_entries() -> libnss::interop::Response<Vec<Passwd>> {
-        let access_token = match crate::AUTH.lock().unwrap().get_access_token() {
-            Ok(token) => token.clone(),
-            Err(err) => {
-                log::error!("Failed to get access token: {:?}", err);
-                return Response::TryAgain;
-            }
-        };
-        match list_users(
-            &crate::CONFIG.keycloak,
-            &crate::CONFIG.mapping,
-            &access_token,
-        ) {
-            Ok(users) => {
-                Response::Success(users.into_iter().map(|user| Passwd::from(user)).collect())
-            }
-            Err(err) => {
-                log::error!("Failed to get all users: {:?}", err);
-                Response::TryAgain
-            }
-        }
+    fn get_all_entries() -> Response<Vec<Passwd>> {
+        crate::daemon::resolve(Request::AllUsers, || {
+            crate::cached_enumeration(
+                "get all users",
+                &crate::CACHE.users,
+                |config, client, access_token| {
+                    let users =
+                        list_users(&config.keycloak, &config.mapping, access_token, client)?;
+                    Ok(Some(users.into_iter().map(Passwd::from).collect()))
+                },
+            )
+        })
     }
 
-    fn get_entry_by_uid(uid: libc::uid_t) -> libnss::interop::Response<Passwd> {
-        let access_token = match crate::AUTH.lock().unwrap().get_access_token() {
-            Ok(token) => token.clone(),
-            Err(err) => {
-                log::error!("Failed to get access token: {:?}", err);
-                return Response::TryAgain;
-            }
-        };
-        match get_user_by_uid(
-            &crate::CONFIG.keycloak,
-            &crate::CONFIG.mapping,
-            &access_token,
-            uid,
-        ) {
-            Ok(Some(user)) => Response::Success(Passwd::from(user)),
-            Ok(None) => Response::NotFound,
-            Err(err) => {
-                log::error!("Failed to get user by uid: {:?}", err);
-                Response::TryAgain
-            }
-        }
+    fn get_entry_by_uid(uid: libc::uid_t) -> Response<Passwd> {
+        crate::daemon::resolve(Request::UserByUid(uid), || {
+            crate::cached_lookup(
+                "get user by uid",
+                &crate::CACHE.users,
+                Key::Id(uid),
+                |config, client, access_token| {
+                    let user = get_user_by_uid(
+                        &config.keycloak,
+                        &config.mapping,
+                        access_token,
+                        uid,
+                        client,
+                    )?;
+                    Ok(user.map(Passwd::from))
+                },
+            )
+        })
     }
 
-    fn get_entry_by_name(name: String) -> libnss::interop::Response<Passwd> {
-        let access_token = match crate::AUTH.lock().unwrap().get_access_token() {
-            Ok(token) => token.clone(),
-            Err(err) => {
-                log::error!("Failed to get access token: {:?}", err);
-                return Response::TryAgain;
-            }
-        };
-        match get_user_by_name(
-            &crate::CONFIG.keycloak,
-            &crate::CONFIG.mapping,
-            &access_token,
-            &name,
-        ) {
-            Ok(Some(user)) => Response::Success(Passwd::from(user)),
-            Ok(None) => Response::NotFound,
-            Err(err) => {
-                log::error!("Failed to get user by name: {:?}", err);
-                Response::TryAgain
-            }
-        }
+    fn get_entry_by_name(name: String) -> Response<Passwd> {
+        crate::daemon::resolve(Request::UserByName(name.clone()), || {
+            crate::cached_lookup(
+                "get user by name",
+                &crate::CACHE.users,
+                Key::Name(&name),
+                |config, client, access_token| {
+                    let user = get_user_by_name(
+                        &config.keycloak,
+                        &config.mapping,
+                        access_token,
+                        &name,
+                        client,
+                    )?;
+                    Ok(user.map(Passwd::from))
+                },
+            )
+        })
     }
 }
@@ -0,0 +1,71 @@
+use libnss::interop::Response;
+use libnss::shadow::{Shadow, ShadowHooks};
+
+use crate::daemon::Request;
+use crate::keycloak::users::{get_user_by_name, list_users, KeycloakUser};
+
+/// value of an empty numeric field in the shadow database
+const SHADOW_FIELD_UNSET: isize = -1;
+/// expiration date (in days since epoch) that marks the account as expired
+const SHADOW_ACCOUNT_EXPIRED: isize = 1;
+
+pub struct KeycloakNssShadow;
+
+impl From<KeycloakUser> for Shadow {
+    /// Keycloak does not expose password hashes, so the password field never
+    /// contains a usable hash. Disabled users are marked as locked ("!") and
+    /// expired, so that `passwd -S` reports them as locked and `pam_unix`
+    /// account checks reject them.
+    fn from(user: KeycloakUser) -> Self {
+        let aging = user.password_aging;
+        let days = |value: Option<i64>| value.map_or(SHADOW_FIELD_UNSET, |days| days as isize);
+        Shadow {
+            name: user.username,
+            passwd: if user.enabled { "*" } else { "!*" }.to_string(),
+            last_change: days(aging.last_change),
+            change_min_days: days(aging.min_days),
+            change_max_days: days(aging.max_days),
+            change_warn_days: days(aging.warn_days),
+            change_inactive_days: days(aging.inactive_days),
+            expire_date: if user.enabled {
+                days(aging.expire)
+            } else {
+                SHADOW_ACCOUNT_EXPIRED
+            },
+            reserved: 0,
+        }
+    }
+}
+
+impl ShadowHooks for KeycloakNssShadow {
+    /// Get all shadow entries from Keycloak
+    /// calls keycloak::list_users underneath
+    fn get_all_entries() -> Response<Vec<Shadow>> {
+        crate::daemon::resolve(Request::AllShadow, || {
+            crate::lookup("get all shadow entries", |config, client, access_token| {
+                let users = list_users(&config.keycloak, &config.mapping, access_token, client)?;
+                Ok(Some(users.into_iter().map(Shadow::from).collect()))
+            })
+        })
+    }
+
+    /// Get a shadow entry by user name
+    /// calls keycloak::get_user_by_name underneath
+    fn get_entry_by_name(name: String) -> Response<Shadow> {
+        crate::daemon::resolve(Request::ShadowByName(name.clone()), || {
+            crate::lookup(
+                "get shadow entry by name",
+                |config, client, access_token| {
+                    let user = get_user_by_name(
+                        &config.keycloak,
+                        &config.mapping,
+                        access_token,
+                        &name,
+                        client,
+                    )?;
+                    Ok(user.map(Shadow::from))
+                },
+            )
+        })
+    }
+}
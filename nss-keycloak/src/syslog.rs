@@ -0,0 +1,78 @@
+use std::ffi::CString;
+use std::sync::Once;
+
+// the plugin runs inside arbitrary processes, whose syslog identity is kept
+const PREFIX: &str = "nss_keycloak";
+
+static INIT: Once = Once::new();
+
+/// Log to syslog, as the processes loading the plugin have no logger of their own
+struct SyslogLogger;
+
+/// Syslog priority of a log level
+fn priority(level: log::Level) -> libc::c_int {
+    let severity = match level {
+        log::Level::Error => libc::LOG_ERR,
+        log::Level::Warn => libc::LOG_WARNING,
+        log::Level::Info => libc::LOG_INFO,
+        log::Level::Debug | log::Level::Trace => libc::LOG_DEBUG,
+    };
+    libc::LOG_AUTHPRIV | severity
+}
+
+impl log::Log for SyslogLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = format!("{}: {}", PREFIX, record.args()).replace('\0', " ");
+        let Ok(message) = CString::new(message) else {
+            return;
+        };
+        // SAFETY: both strings are NUL-terminated, and the format consumes one string
+        unsafe {
+            libc::syslog(priority(record.level()), c"%s".as_ptr(), message.as_ptr());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the syslog logger once per process, unless the process has set a logger
+/// itself, like nss-keycloakd. Only warnings and errors are logged.
+pub fn init() {
+    INIT.call_once(|| {
+        if log::set_logger(&SyslogLogger).is_ok() {
+            log::set_max_level(log::LevelFilter::Warn);
+        }
+    });
+}
+
+// -----------------------------------------------------------------------------------------------
+// --- Unit tests -------------------------------------------------------------------------------
+// -----------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that log levels are mapped to the syslog severities of the auth facility
+    #[test]
+    fn test_priority() {
+        assert_eq!(
+            priority(log::Level::Error),
+            libc::LOG_AUTHPRIV | libc::LOG_ERR
+        );
+        assert_eq!(
+            priority(log::Level::Warn),
+            libc::LOG_AUTHPRIV | libc::LOG_WARNING
+        );
+        assert_eq!(
+            priority(log::Level::Trace),
+            libc::LOG_AUTHPRIV | libc::LOG_DEBUG
+        );
+    }
+}
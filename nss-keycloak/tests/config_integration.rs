@@ -1,5 +1,3 @@
-use temp_env;
-
 use nss_keycloak::CONFIG;
 
 #[test]
@@ -8,23 +6,24 @@ fn test_get_config_path_from_env() {
         "NSSKEYCLOAK_CONFIG_FILE",
         Some("tests/files/config.toml"),
         || {
+            let config = CONFIG.as_ref().expect("Failed to load configuration");
             // config.keycloak
-            assert_eq!(CONFIG.keycloak.realm, "test");
-            assert_eq!(CONFIG.keycloak.client_id, "nss-client");
+            assert_eq!(config.keycloak.realm, "test");
+            assert_eq!(config.keycloak.client_id, "nss-client");
             assert_eq!(
-                CONFIG.keycloak.client_secret,
+                config.keycloak.client_secret,
                 "UO2i2h5Vku3oQBvFIlsMG23pIMZyRJOi"
             );
-            assert_eq!(CONFIG.keycloak.url, "http://localhost:8080");
-            assert_eq!(CONFIG.keycloak.username, "nss-user");
-            assert_eq!(CONFIG.keycloak.password, "nss-user");
+            assert_eq!(config.keycloak.url, "http://localhost:8080");
+            assert_eq!(config.keycloak.username.as_deref(), Some("nss-user"));
+            assert_eq!(config.keycloak.password.as_deref(), Some("nss-user"));
             // config.mapping
-            assert_eq!(CONFIG.mapping.user_home, "homedirectory");
-            assert_eq!(CONFIG.mapping.user_shell, "loginshell");
-            assert_eq!(CONFIG.mapping.user_gecos, "gecos");
-            assert_eq!(CONFIG.mapping.user_uid, "uidnumber");
-            assert_eq!(CONFIG.mapping.user_gid, "gidnumber");
-            assert_eq!(CONFIG.mapping.group_gid, "gidnumber");
+            assert_eq!(config.mapping.user_home, "homedirectory");
+            assert_eq!(config.mapping.user_shell, "loginshell");
+            assert_eq!(config.mapping.user_gecos, "gecos");
+            assert_eq!(config.mapping.user_uid, "uidnumber");
+            assert_eq!(config.mapping.user_gid, "gidnumber");
+            assert_eq!(config.mapping.group_gid, "gidnumber");
         },
     );
 }
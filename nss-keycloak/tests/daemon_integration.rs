@@ -0,0 +1,50 @@
+use libnss::interop::Response;
+use libnss::passwd::Passwd;
+use libnss::shadow::Shadow;
+
+use nss_keycloak::daemon::{query, serve, Request};
+
+#[test]
+fn test_daemon_lookups() {
+    temp_env::with_var(
+        "NSSKEYCLOAK_CONFIG_FILE",
+        Some("tests/files/config.toml"),
+        || {
+            let directory = tempfile::tempdir().unwrap();
+            let socket = directory.path().join("nss-keycloakd.sock");
+            let socket = socket.to_str().unwrap().to_string();
+            // no daemon listening yet, so the plugin has to resolve directly
+            assert!(query::<Passwd>(&socket, &Request::UserByUid(1000)).is_none());
+
+            let path = socket.clone();
+            std::thread::spawn(move || serve(&path).unwrap());
+            while !std::path::Path::new(&socket).exists() {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+
+            match query::<Passwd>(&socket, &Request::UserByName("user01".to_string())) {
+                Some(Response::Success(passwd)) => {
+                    assert_eq!(passwd.name, "user01");
+                    assert_eq!(passwd.uid, 1000);
+                    assert_eq!(passwd.dir, "/home/user01");
+                }
+                _ => panic!("Failed to get user01 from the daemon"),
+            }
+            assert!(matches!(
+                query::<Passwd>(&socket, &Request::UserByName("user99".to_string())),
+                Some(Response::NotFound)
+            ));
+            match query::<Vec<Passwd>>(&socket, &Request::AllUsers) {
+                Some(Response::Success(passwds)) => assert_eq!(passwds.len(), 2),
+                _ => panic!("Failed to get all users from the daemon"),
+            }
+            // shadow entries are only returned to root
+            let shadow = query::<Shadow>(&socket, &Request::ShadowByName("user01".to_string()));
+            if unsafe { libc::geteuid() } == 0 {
+                assert!(matches!(shadow, Some(Response::Success(_))));
+            } else {
+                assert!(matches!(shadow, Some(Response::Unavail)));
+            }
+        },
+    );
+}
@@ -0,0 +1,55 @@
+use libnss::initgroups::InitgroupsHooks;
+use libnss::interop::Response;
+
+fn response_type_as_str<T>(response: &Response<T>) -> &str {
+    match response {
+        Response::Success(_) => "Success",
+        Response::NotFound => "NotFound",
+        Response::Unavail => "Unavail",
+        Response::TryAgain => "TryAgain",
+        Response::Return => "Return",
+    }
+}
+
+#[test]
+fn test_get_entries_by_user() {
+    temp_env::with_var(
+        "NSSKEYCLOAK_CONFIG_FILE",
+        Some("tests/files/config.toml"),
+        || {
+            let response =
+                nss_keycloak::KeycloakNssInitgroups::get_entries_by_user("user02".to_string());
+            match response {
+                Response::Success(groups) => {
+                    assert_eq!(groups.len(), 1);
+                    assert_eq!(groups[0].name, "group02");
+                    assert_eq!(groups[0].gid, 501);
+                }
+                _ => panic!(
+                    "Failed to get groups by user. Expected Respose::Success, got {}",
+                    response_type_as_str(&response),
+                ),
+            }
+        },
+    );
+}
+
+#[test]
+fn test_get_entries_by_user_not_found() {
+    temp_env::with_var(
+        "NSSKEYCLOAK_CONFIG_FILE",
+        Some("tests/files/config.toml"),
+        || {
+            let response = nss_keycloak::KeycloakNssInitgroups::get_entries_by_user(
+                "does_not_exist".to_string(),
+            );
+            match response {
+                Response::NotFound => (), // expected
+                _ => panic!(
+                    "Failed to get groups by user. Expected Respose::NotFound, got {}",
+                    response_type_as_str(&response),
+                ),
+            }
+        },
+    );
+}
@@ -0,0 +1,26 @@
+use libnss::group::GroupHooks;
+use libnss::interop::Response;
+use libnss::passwd::PasswdHooks;
+
+#[test]
+fn test_hooks_with_missing_config() {
+    temp_env::with_var(
+        "NSSKEYCLOAK_CONFIG_FILE",
+        Some("tests/files/does-not-exist.toml"),
+        || {
+            // the configuration error is returned by every hook, without panicking
+            for _ in 0..2 {
+                assert!(matches!(
+                    nss_keycloak::KeycloakNssPasswd::get_entry_by_name("user01".to_string()),
+                    Response::Unavail
+                ));
+                assert!(matches!(
+                    nss_keycloak::KeycloakNssGroup::get_all_entries(),
+                    Response::Unavail
+                ));
+            }
+            assert!(nss_keycloak::CONFIG.is_err());
+            assert!(nss_keycloak::AUTH.is_err());
+        },
+    );
+}
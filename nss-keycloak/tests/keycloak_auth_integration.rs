@@ -1,11 +1,16 @@
+// most of this file is only used with the 'mock' feature enabled
+#![cfg_attr(not(feature = "mock"), allow(unused_imports, dead_code))]
+
 use std::ops::Add;
 use std::time::Duration;
 
 use anyhow::Result;
 use mock_instant::MockClock;
 
-use nss_keycloak::config::KeycloakConfig;
-use nss_keycloak::keycloak::auth::TokenProvider;
+use nss_keycloak::config::{self, KeycloakConfig};
+use nss_keycloak::keycloak::auth::{KeycloakAuth, TokenProvider};
+use nss_keycloak::keycloak::client::HttpClient;
+use nss_keycloak::keycloak::error::{KeycloakError, OAuthErrorCode};
 use nss_keycloak::{AUTH, CONFIG};
 
 /// validate token using the Keycloak token introspection endpoint
@@ -17,7 +22,7 @@ fn validate_token(config: &KeycloakConfig, access_token: &str) -> Result<()> {
 
     let client = reqwest::blocking::Client::new();
     let response = client
-        .post(&format!(
+        .post(format!(
             "{}/realms/{}/protocol/openid-connect/token/introspect",
             config.url, config.realm,
         ))
@@ -47,34 +52,36 @@ fn test_keycloak_auth_get_token() {
         "NSSKEYCLOAK_CONFIG_FILE",
         Some("tests/files/config.toml"),
         || {
-            let access_token01 = AUTH.lock().unwrap().get_access_token().unwrap().to_owned();
-            validate_token(&CONFIG.keycloak, &access_token01).expect("Token validation failed");
+            let auth = AUTH.as_ref().expect("Failed to initialize authentication");
+            let config = CONFIG.as_ref().expect("Failed to load configuration");
+            let access_token01 = auth.lock().unwrap().get_access_token().unwrap().to_owned();
+            validate_token(&config.keycloak, &access_token01).expect("Token validation failed");
 
             // expiration time of the access token (plus 1 second to be above that)
-            let access_token_exires_in = AUTH
+            let access_token_exires_in = auth
                 .lock()
                 .unwrap()
                 .access_token_expires_in()
                 .unwrap()
                 .add(Duration::from_secs(1));
             MockClock::advance_system_time(access_token_exires_in);
-            let access_token02 = AUTH.lock().unwrap().get_access_token().unwrap().to_owned();
-            validate_token(&CONFIG.keycloak, &access_token02).expect("Token validation failed");
+            let access_token02 = auth.lock().unwrap().get_access_token().unwrap().to_owned();
+            validate_token(&config.keycloak, &access_token02).expect("Token validation failed");
 
             // the access tokens should be different because it has been updated
             // using the refresh operation
             assert_ne!(access_token01, access_token02);
 
             // expiration time of the refresh token (plus 1 second to be above that)
-            let refresh_token_expires_in = AUTH
+            let refresh_token_expires_in = auth
                 .lock()
                 .unwrap()
                 .refresh_token_expires_in()
                 .unwrap()
                 .add(Duration::from_secs(1));
             MockClock::advance_system_time(refresh_token_expires_in);
-            let access_token03 = AUTH.lock().unwrap().get_access_token().unwrap().to_owned();
-            validate_token(&CONFIG.keycloak, &access_token03).expect("Token validation failed");
+            let access_token03 = auth.lock().unwrap().get_access_token().unwrap().to_owned();
+            validate_token(&config.keycloak, &access_token03).expect("Token validation failed");
 
             // the access tokens should be different because it has been updated
             // with a full re-authentication
@@ -82,3 +89,72 @@ fn test_keycloak_auth_get_token() {
         },
     );
 }
+
+#[test]
+fn test_keycloak_auth_rejected_credentials() {
+    // warn if the 'mock' feature is not enabled
+    // required for modifying the system time
+    #[cfg(not(feature = "mock"))]
+    panic!("This test requires the 'mock' feature to be enabled");
+
+    #[cfg(feature = "mock")]
+    {
+        let mut config = config::read_config_file("tests/files/config.toml").unwrap();
+        config.keycloak.client_secret = "wrongsecret".to_string();
+        let client = HttpClient::new(&config.keycloak);
+        let mut auth = KeycloakAuth::new(&config.keycloak, &client).unwrap();
+
+        // the OAuth error of the token endpoint is returned as a typed error
+        let err = auth.get_access_token().unwrap_err();
+        assert!(matches!(
+            KeycloakError::find(&err),
+            Some(KeycloakError::OAuth(oauth)) if oauth.code == OAuthErrorCode::InvalidClient
+        ));
+
+        // the rejected credentials are not sent again until the backoff has passed
+        let err = auth.get_access_token().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("rejected the credentials recently"));
+        MockClock::advance_system_time(Duration::from_secs(
+            config.keycloak.bad_credentials_backoff + 1,
+        ));
+        let err = auth.get_access_token().unwrap_err();
+        assert!(!err
+            .to_string()
+            .contains("rejected the credentials recently"));
+        assert!(matches!(
+            KeycloakError::find(&err),
+            Some(KeycloakError::OAuth(_))
+        ));
+    }
+}
+
+#[test]
+fn test_keycloak_auth_rejected_credentials_shared() {
+    let mut config = config::read_config_file("tests/files/config.toml").unwrap();
+    config.keycloak.client_secret = "wrongsecret".to_string();
+    let directory = tempfile::tempdir().unwrap();
+    let directory = directory.path().to_str();
+    let client = HttpClient::new(&config.keycloak);
+    let mut auth = KeycloakAuth::new(&config.keycloak, &client)
+        .unwrap()
+        .with_state_directory(directory);
+    let err = auth.get_access_token().unwrap_err();
+    assert!(!err
+        .to_string()
+        .contains("rejected the credentials recently"));
+
+    // another process, with its own KeycloakAuth, does not send the credentials either
+    let mut other = KeycloakAuth::new(&config.keycloak, &client)
+        .unwrap()
+        .with_state_directory(directory);
+    let err = other.get_access_token().unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("rejected the credentials recently"));
+    assert!(matches!(
+        KeycloakError::find(&err),
+        Some(KeycloakError::OAuth(oauth)) if oauth.code == OAuthErrorCode::InvalidClient
+    ));
+}
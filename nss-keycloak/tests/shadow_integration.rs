@@ -0,0 +1,79 @@
+use libnss::interop::Response;
+use libnss::shadow::ShadowHooks;
+
+fn response_type_as_str<T>(response: &Response<T>) -> &str {
+    match response {
+        Response::Success(_) => "Success",
+        Response::NotFound => "NotFound",
+        Response::Unavail => "Unavail",
+        Response::TryAgain => "TryAgain",
+        Response::Return => "Return",
+    }
+}
+
+#[test]
+fn test_shadow_get_all_entries() {
+    temp_env::with_var(
+        "NSSKEYCLOAK_CONFIG_FILE",
+        Some("tests/files/config.toml"),
+        || {
+            let response = nss_keycloak::KeycloakNssShadow::get_all_entries();
+            match response {
+                Response::Success(shadows) => {
+                    assert_eq!(shadows.len(), 2);
+                    assert_eq!(shadows[0].name, "user01");
+                    assert_eq!(shadows[0].passwd, "*");
+                    assert_eq!(shadows[1].name, "user02");
+                    assert_eq!(shadows[1].passwd, "*");
+                }
+                _ => panic!(
+                    "Failed to get all shadow entries. Expected Respose::Success, got {}",
+                    response_type_as_str(&response),
+                ),
+            }
+        },
+    );
+}
+
+#[test]
+fn test_shadow_get_entry_by_name_found() {
+    temp_env::with_var(
+        "NSSKEYCLOAK_CONFIG_FILE",
+        Some("tests/files/config.toml"),
+        || {
+            let response = nss_keycloak::KeycloakNssShadow::get_entry_by_name("user01".to_string());
+            match response {
+                Response::Success(shadow) => {
+                    assert_eq!(shadow.name, "user01");
+                    assert_eq!(shadow.passwd, "*");
+                    assert_eq!(shadow.last_change, -1);
+                    assert_eq!(shadow.change_max_days, -1);
+                    assert_eq!(shadow.expire_date, -1);
+                }
+                _ => panic!(
+                    "Failed to get shadow entry by name. Expected Respose::Success, got {}",
+                    response_type_as_str(&response),
+                ),
+            }
+        },
+    );
+}
+
+#[test]
+fn test_shadow_get_entry_by_name_not_found() {
+    temp_env::with_var(
+        "NSSKEYCLOAK_CONFIG_FILE",
+        Some("tests/files/config.toml"),
+        || {
+            let response =
+                nss_keycloak::KeycloakNssShadow::get_entry_by_name("does_not_exist".to_string());
+            match response {
+                Response::NotFound => (), // expected
+                _ => panic!(
+                    "Failed to get shadow entry by name. Expected Respose::NotFound, got {}",
+                    response_type_as_str(&response),
+                ),
+            }
+        },
+    );
+}